@@ -0,0 +1,1280 @@
+use crate::attrs::AttrsExt;
+use crate::contents::ContentsExt;
+use crate::db::DBExt;
+use crate::error::DirtabaseError;
+use crate::mime;
+use arkive::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::rc::Rc;
+
+/// Digest of an archive with nothing in it, which is what `--empty` pushes.
+///
+/// Contents don't matter when there aren't any, so this is the same whatever
+/// `C` the empty archive had.
+pub const EMPTY_ARCHIVE_DIGEST: &str =
+    "5ae1625b488b3935122d8dd627fe575b388a5aa360378fa4407aad08baaed1e2";
+
+/// `EMPTY_ARCHIVE_DIGEST`, parsed.
+pub fn empty_digest() -> Digest {
+    Digest::from_hex(EMPTY_ARCHIVE_DIGEST).expect("valid hex")
+}
+
+/// Extra behavior for any `Ark`, which lives upstream in arkive.
+pub trait ArkExt<C>: Sized {
+    /// Convert to a new content type, keeping paths and attrs as they are.
+    ///
+    /// This is the common shape behind things like reading file contents from
+    /// disk or a DB. The function gets each file's path too, which is mostly
+    /// useful for good error messages. Stops at the first error.
+    fn try_translate<T>(self, f: impl FnMut(&IPR, &C) -> Result<T>) -> Result<Ark<T>>;
+
+    /// Infallible version of `try_translate`.
+    fn translate<T>(self, mut f: impl FnMut(&IPR, &C) -> T) -> Ark<T> {
+        self.try_translate(|ipr, c| Ok(f(ipr, c)))
+            .expect("Infallible translation failed")
+    }
+
+    /// Iterate all entries, files then dirs, without cloning anything.
+    ///
+    /// Same order as `to_entries()`, which is handy when you just want to
+    /// look rather than take ownership.
+    fn iter(&self) -> EntryIterator<'_, C>;
+
+    /// How many entries are files. Files come first, so this is also where
+    /// the dirs start in `paths()` and `attrs()`.
+    fn len_files(&self) -> usize;
+
+    /// How many entries are dirs.
+    fn len_dirs(&self) -> usize;
+
+    /// No files and no dirs.
+    fn is_empty(&self) -> bool;
+
+    /// Look up what's at a path, in O(log n).
+    ///
+    /// Files and dirs are each sorted within their own section, so this is
+    /// just a binary search in each.
+    fn get(&self, path: impl Into<IPR>) -> Option<Contents<&C>>;
+
+    /// The `n`th file, counting from 0. `None` past the last one.
+    fn file_at(&self, n: usize) -> Option<(&IPR, &Attrs, &C)>;
+
+    /// The `n`th dir, counting from 0 (not from the first file).
+    fn dir_at(&self, n: usize) -> Option<(&IPR, &Attrs)>;
+
+    /// Replace every entry's attrs with `f(path, attrs)`, files then dirs.
+    ///
+    /// Paths and contents are shared with the original rather than copied,
+    /// since attrs can't affect ordering or uniqueness.
+    fn rewrite_attrs(self, f: impl FnMut(&IPR, Attrs) -> Attrs) -> Ark<C>;
+
+    /// Keep only the entries where `keep(path, attrs)` is true.
+    ///
+    /// Dropping entries can't reorder the rest, so there's no re-sort like
+    /// `from_entries` would do. Dirs are judged on their own, the same as
+    /// files, so a kept file can lose its parent dir's entry.
+    fn filter(self, keep: impl FnMut(&IPR, &Attrs) -> bool) -> Ark<C>
+    where
+        C: Clone;
+
+    /// Move every entry under `prefix`, so `a/b` becomes `prefix/a/b`.
+    ///
+    /// Only paths change, and prepending the same thing to all of them can't
+    /// reorder them, so attrs and contents are shared with the original. No
+    /// entry is added for `prefix` itself. An empty prefix is a no-op.
+    fn prefix(self, prefix: impl Into<IPR>) -> Ark<C>;
+
+    /// Undo `prefix`: move every entry out from under `prefix`.
+    ///
+    /// A dir at `prefix` itself is dropped. Anything else that isn't under
+    /// `prefix` (including a file at `prefix`) is an `InvalidInput` error.
+    fn strip_prefix(self, prefix: impl Into<IPR>) -> Result<Ark<C>>;
+
+    /// Strip a single top-level directory that wraps everything else.
+    ///
+    /// Tarballs love to do this (`lua-5.4.7/...`). Only one level is removed.
+    /// If there's more than one top-level entry, or the only one is a file,
+    /// the archive is returned as-is.
+    fn flatten(self) -> Ark<C>;
+
+    /// Drop the parts of attrs that vary between machines.
+    ///
+    /// `UNIX_MODE` keeps its file type bits, but permissions collapse to 644
+    /// or 755 (like git does), so a different umask doesn't change the
+    /// digest. Attrs are also canonicalized, so insertion order can't either.
+    fn reproducible(self) -> Ark<C>;
+
+    /// Give every file a `MIME` attr, guessed from its extension.
+    ///
+    /// Files that already have one are left alone.
+    fn tag_mime(self) -> Ark<C>;
+
+    /// Check the layout that `Ark` promises (see `paths()` upstream).
+    ///
+    /// Anything built by arkive's constructors already holds up, but a
+    /// deserialized Ark is only as trustworthy as the JSON it came from.
+    fn validate(&self) -> std::result::Result<(), InvalidArk>;
+
+    /// Decode JSON, then `validate` it.
+    fn from_json(json: &[u8]) -> Result<Ark<C>>
+    where
+        C: DeserializeOwned;
+}
+
+/// Ways an archive can break the invariants other code relies on.
+#[derive(Debug, PartialEq)]
+pub enum InvalidArk {
+    Lengths {
+        paths: usize,
+        attrs: usize,
+        contents: usize,
+    },
+    /// Out of order or duplicated within the files or dirs section.
+    Unsorted(IPR),
+    FileAndDir(IPR),
+}
+impl From<InvalidArk> for Error {
+    fn from(ia: InvalidArk) -> Self {
+        Self::new(
+            ErrorKind::InvalidData,
+            match ia {
+                InvalidArk::Lengths {
+                    paths,
+                    attrs,
+                    contents,
+                } => format!(
+                    "Archive has {} paths, {} attrs and {} contents",
+                    paths, attrs, contents
+                ),
+                InvalidArk::Unsorted(ipr) => {
+                    format!("Archive path {:?} is out of order", ipr.as_ref())
+                }
+                InvalidArk::FileAndDir(ipr) => {
+                    format!("Archive path {:?} is both a file and a dir", ipr.as_ref())
+                }
+            },
+        )
+    }
+}
+
+impl<C> ArkExt<C> for Ark<C> {
+    fn try_translate<T>(self, mut f: impl FnMut(&IPR, &C) -> Result<T>) -> Result<Ark<T>> {
+        let (paths, attrs, contents) = self.decompose();
+        let translated: Result<Vec<T>> = paths
+            .iter()
+            .zip(contents.iter())
+            .map(|(ipr, c)| f(ipr, c))
+            .collect();
+        Ok(Ark::compose(paths, attrs, Rc::new(translated?)))
+    }
+
+    fn iter(&self) -> EntryIterator<'_, C> {
+        EntryIterator {
+            inner: self,
+            pos: 0,
+        }
+    }
+
+    fn len_files(&self) -> usize {
+        self.contents().len()
+    }
+
+    fn len_dirs(&self) -> usize {
+        self.len() - self.len_files()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, path: impl Into<IPR>) -> Option<Contents<&C>> {
+        let path = path.into();
+        let (files, dirs) = self.paths().split_at(self.len_files());
+        if let Ok(pos) = files.binary_search(&path) {
+            Some(Contents::File(&self.contents()[pos]))
+        } else if dirs.binary_search(&path).is_ok() {
+            Some(Contents::Dir)
+        } else {
+            None
+        }
+    }
+
+    fn file_at(&self, n: usize) -> Option<(&IPR, &Attrs, &C)> {
+        let contents = self.contents().get(n)?;
+        Some((&self.paths()[n], &self.attrs()[n], contents))
+    }
+
+    fn dir_at(&self, n: usize) -> Option<(&IPR, &Attrs)> {
+        let pos = self.len_files().checked_add(n)?;
+        Some((self.paths().get(pos)?, &self.attrs()[pos]))
+    }
+
+    fn flatten(self) -> Ark<C> {
+        let top = match self.paths().first() {
+            Some(first) => first.as_ref().split('/').next().unwrap_or("").to_owned(),
+            None => return self,
+        };
+        let wrapper = top.clone() + "/";
+        let has_children = self
+            .paths()
+            .iter()
+            .any(|p| p.as_ref().starts_with(&wrapper));
+        let all_under = self
+            .paths()
+            .iter()
+            .all(|p| p.as_ref() == top || p.as_ref().starts_with(&wrapper));
+        if !has_children || !all_under {
+            return self;
+        }
+        self.strip_prefix(top.as_str())
+            .expect("everything is under the wrapper")
+    }
+
+    fn prefix(self, prefix: impl Into<IPR>) -> Ark<C> {
+        let prefix = prefix.into();
+        if prefix.as_ref().is_empty() {
+            return self;
+        }
+        let (paths, attrs, contents) = self.decompose();
+        let paths: Vec<IPR> = paths
+            .iter()
+            .map(|p| IPR::from(format!("{}/{}", prefix.as_ref(), p.as_ref())))
+            .collect();
+        Ark::compose(Rc::new(paths), attrs, contents)
+    }
+
+    fn strip_prefix(self, prefix: impl Into<IPR>) -> Result<Ark<C>> {
+        let prefix = prefix.into();
+        if prefix.as_ref().is_empty() {
+            return Ok(self);
+        }
+        let under = format!("{}/", prefix.as_ref());
+        let len_files = self.len_files();
+
+        // Stripping a shared prefix doesn't change sort order, and only a dir
+        // gets dropped, so contents are untouched.
+        let (paths, attrs, contents) = self.decompose();
+        let mut kept = (vec![], vec![]);
+        for (n, (p, a)) in paths.iter().zip(attrs.iter()).enumerate() {
+            if n >= len_files && p.as_ref() == prefix.as_ref() {
+                continue;
+            }
+            let Some(rest) = p.as_ref().strip_prefix(&under) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{:?} isn't under {:?}", p.as_ref(), prefix.as_ref()),
+                ));
+            };
+            kept.0.push(IPR::from(rest));
+            kept.1.push(a.clone());
+        }
+        Ok(Ark::compose(Rc::new(kept.0), Rc::new(kept.1), contents))
+    }
+
+    fn filter(self, mut keep: impl FnMut(&IPR, &Attrs) -> bool) -> Ark<C>
+    where
+        C: Clone,
+    {
+        let len_files = self.len_files();
+        let (paths, attrs, contents) = self.decompose();
+        let mut kept = (vec![], vec![], vec![]);
+        for (n, (p, a)) in paths.iter().zip(attrs.iter()).enumerate() {
+            if !keep(p, a) {
+                continue;
+            }
+            kept.0.push(p.clone());
+            kept.1.push(a.clone());
+            // Files come first, so contents stay lined up with them.
+            if n < len_files {
+                kept.2.push(contents[n].clone());
+            }
+        }
+        Ark::compose(Rc::new(kept.0), Rc::new(kept.1), Rc::new(kept.2))
+    }
+
+    fn rewrite_attrs(self, mut f: impl FnMut(&IPR, Attrs) -> Attrs) -> Ark<C> {
+        let (paths, attrs, contents) = self.decompose();
+        let attrs = Rc::try_unwrap(attrs).unwrap_or_else(|shared| (*shared).clone());
+        let attrs: Vec<Attrs> = paths.iter().zip(attrs).map(|(ipr, a)| f(ipr, a)).collect();
+        Ark::compose(paths, Rc::new(attrs), contents)
+    }
+
+    fn reproducible(self) -> Ark<C> {
+        let n_files = self.len_files();
+        let mut n = 0;
+        self.rewrite_attrs(|_, a| {
+            let is_dir = n >= n_files;
+            n += 1;
+            let a = match a.unix_mode() {
+                Ok(Some(mode)) => a.set_unix_mode(normal_mode(mode, is_dir)),
+                _ => a,
+            };
+            a.canonicalize()
+        })
+    }
+
+    fn tag_mime(self) -> Ark<C> {
+        let (paths, attrs, contents) = self.decompose();
+        let attrs: Vec<Attrs> = attrs
+            .iter()
+            .enumerate()
+            .map(|(n, a)| {
+                if n < contents.len() && a.get("MIME").is_none() {
+                    a.clone().append("MIME", mime::guess(paths[n].as_ref()))
+                } else {
+                    a.clone()
+                }
+            })
+            .collect();
+        Ark::compose(paths, Rc::new(attrs), contents)
+    }
+
+    fn validate(&self) -> std::result::Result<(), InvalidArk> {
+        let (paths, attrs, contents) = (
+            self.paths().len(),
+            self.attrs().len(),
+            self.contents().len(),
+        );
+        if paths != attrs || contents > paths {
+            return Err(InvalidArk::Lengths {
+                paths,
+                attrs,
+                contents,
+            });
+        }
+
+        let (files, dirs) = self.paths().split_at(contents);
+        for section in [files, dirs] {
+            if let Some(pair) = section.windows(2).find(|pair| pair[0] >= pair[1]) {
+                return Err(InvalidArk::Unsorted(pair[1].clone()));
+            }
+        }
+        match dirs.iter().find(|dir| files.binary_search(dir).is_ok()) {
+            Some(dir) => Err(InvalidArk::FileAndDir(dir.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn from_json(json: &[u8]) -> Result<Ark<C>>
+    where
+        C: DeserializeOwned,
+    {
+        let ark: Ark<C> = serde_json::from_slice(json)?;
+        ark.validate()?;
+        Ok(ark)
+    }
+}
+
+/// Keep the file type, but only care whether permissions are executable.
+fn normal_mode(mode: u32, is_dir: bool) -> u32 {
+    let perms = if is_dir || mode & 0o111 != 0 {
+        0o755
+    } else {
+        0o644
+    };
+    (mode & 0o170000) | perms
+}
+
+pub struct EntryIterator<'a, C> {
+    inner: &'a Ark<C>,
+    pos: usize,
+}
+impl<'a, C> Iterator for EntryIterator<'a, C> {
+    type Item = (&'a IPR, &'a Attrs, Contents<&'a C>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.pos;
+        let ipr = self.inner.paths().get(pos)?;
+        let contents = Contents::from_option(self.inner.contents().get(pos));
+        self.pos = pos + 1;
+        Some((ipr, &self.inner.attrs()[pos], contents))
+    }
+}
+
+/// Behavior for archives whose file contents live in a DB's CAS.
+pub trait StoredArk {
+    /// Fetch file contents from the DB into memory.
+    ///
+    /// This is the DB counterpart to `Ark<PathBuf>::read()`, and the same
+    /// warning applies: don't do this for anything bigger than your RAM.
+    fn read(&self, db: &DB) -> Result<Ark<Vec<u8>>>;
+
+    /// Like `Ark::load`, but with a useful error if the blob isn't an archive.
+    ///
+    /// A digest can point at anything in the CAS, including plain file
+    /// contents. Upstream `load` panics on non-UTF-8 and gives a raw serde
+    /// error otherwise, neither of which tells you what went wrong.
+    fn load_checked(db: &DB, digest: &Digest) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Like `save`, but skips the write if the CAS already has this archive.
+    ///
+    /// Pipelines produce the same intermediate archives over and over, and
+    /// there's no point rewriting an identical blob. Hashing still happens.
+    fn store(&self, db: &DB) -> Result<Digest>;
+}
+
+impl StoredArk for Ark<Digest> {
+    fn read(&self, db: &DB) -> Result<Ark<Vec<u8>>> {
+        self.clone()
+            .try_translate(|ipr, digest| read_blob(db, digest, ipr))
+    }
+
+    fn store(&self, db: &DB) -> Result<Digest> {
+        let json = self.to_json()?;
+        let digest = Digest::from(&json);
+        if !db.exists(&digest) {
+            // Write to the side and rename, so an interrupted write can't
+            // leave half a blob in the CAS for `exists` to trust later.
+            let tmp = db.tempdir()?;
+            let staged = tmp.path().join(digest.to_hex());
+            std::fs::write(&staged, json)?;
+            std::fs::rename(staged, db.cas_path(&digest))?;
+        }
+        Ok(digest)
+    }
+
+    fn load_checked(db: &DB, digest: &Digest) -> Result<Self> {
+        let bytes = std::fs::read(db.cas_path(digest)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => DirtabaseError::DigestNotFound {
+                digest: *digest,
+                path: None,
+            }
+            .into(),
+            _ => e,
+        })?;
+        let kind = sniff(&bytes);
+        if kind != "JSON" {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Blob {} is not an archive: expected JSON, but it looks like {}",
+                    digest.to_hex(),
+                    kind
+                ),
+            ));
+        }
+        Ark::from_json(&bytes).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Blob {} looks like JSON, but isn't a valid archive: {}",
+                    digest.to_hex(),
+                    e
+                ),
+            )
+        })
+    }
+}
+
+/// Best guess at what's in a blob, going by the first byte.
+fn sniff(bytes: &[u8]) -> &'static str {
+    match bytes.first() {
+        None => "an empty file",
+        Some(b'{') | Some(b'[') => "JSON",
+        // CBOR arrays and maps. Not valid as the first byte of UTF-8.
+        Some(0x80..=0xbf) => "CBOR",
+        _ if std::str::from_utf8(bytes).is_ok() => "plain text",
+        _ => "binary data",
+    }
+}
+
+/// How to resolve two archives having an entry at the same path.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize)]
+pub enum MergeStrategy {
+    /// The later archive's entry replaces the earlier one entirely.
+    #[default]
+    LastWins,
+
+    /// The earlier archive's entry is kept, later ones are ignored.
+    FirstWins,
+
+    /// Like `LastWins`, but attrs are combined rather than replaced.
+    ///
+    /// Every attr name that shows up in any of the conflicting entries is
+    /// kept, using the value from the latest entry that has it. So an overlay
+    /// can add files to a dir without wiping out the dir's `UNIX_MODE`.
+    MergeAttrs,
+}
+
+impl MergeStrategy {
+    /// As given to `--merge`, like `first-wins`.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "last-wins" => Some(Self::LastWins),
+            "first-wins" => Some(Self::FirstWins),
+            "merge-attrs" => Some(Self::MergeAttrs),
+            _ => None,
+        }
+    }
+}
+
+/// `load_checked` a batch of archives, decoding up to `threads` at once.
+///
+/// Decoding is CPU-bound JSON parsing, which is where merging a pile of big
+/// archives spends its time. `Ark` shares its parts through `Rc`, so each one
+/// is taken apart on its worker and put back together here. The results are
+/// in the same order as `digests`, for last-wins merges.
+pub fn load_all(db: &DB, digests: &[Digest], threads: usize) -> Result<Vec<Ark<Digest>>> {
+    fn unwrap<T: Clone>(rc: Rc<T>) -> T {
+        Rc::try_unwrap(rc).unwrap_or_else(|shared| (*shared).clone())
+    }
+    type Parts = (Vec<IPR>, Vec<Attrs>, Vec<Digest>);
+    let mut parts: Vec<Option<Parts>> = digests.iter().map(|_| None).collect();
+    crate::pool::run(
+        threads,
+        digests,
+        |_, digest| {
+            let (paths, attrs, contents) = Ark::<Digest>::load_checked(db, digest)?.decompose();
+            Ok((unwrap(paths), unwrap(attrs), unwrap(contents)))
+        },
+        |n, loaded| parts[n] = Some(loaded),
+    )?;
+    Ok(parts
+        .into_iter()
+        .map(|loaded| {
+            let (paths, attrs, contents) = loaded.expect("every archive loaded");
+            Ark::compose(Rc::new(paths), Rc::new(attrs), Rc::new(contents))
+        })
+        .collect())
+}
+
+/// Combine several archives into one.
+pub fn merge<C>(arks: impl IntoIterator<Item = Ark<C>>, strategy: MergeStrategy) -> Ark<C>
+where
+    C: Clone,
+{
+    let entries = arks.into_iter().flat_map(|ark| ark.to_entries());
+    match strategy {
+        MergeStrategy::LastWins => Ark::from_entries(entries),
+        MergeStrategy::FirstWins => {
+            let mut entries: Vec<_> = entries.collect();
+            entries.reverse();
+            Ark::from_entries(entries)
+        }
+        MergeStrategy::MergeAttrs => {
+            let entries: Vec<_> = entries.collect();
+            let mut combined: HashMap<IPR, Attrs> = HashMap::new();
+            for (ipr, attrs, _) in &entries {
+                let prev = combined.remove(ipr).unwrap_or_else(Attrs::new);
+                combined.insert(ipr.clone(), prev.merge(attrs));
+            }
+            Ark::from_entries(entries.into_iter().map(|(ipr, _, c)| {
+                let attrs = combined[&ipr].clone();
+                (ipr, attrs, c)
+            }))
+        }
+    }
+}
+
+/// Move entries from one prefix to another, for several prefixes at once.
+///
+/// An `old` prefix matches whole path components, so `src` covers `src/a`
+/// but not `srcs/a`. If more than one matches, the longest wins, and paths
+/// that match none are left alone. An empty `new` moves things to the root,
+/// dropping the entry for `old` itself. If two paths land on the same spot,
+/// the one that sorted later wins, same as `--rename`.
+pub fn remap_prefixes<C>(ark: Ark<C>, map: &[(IPR, IPR)]) -> Ark<C>
+where
+    Vec<C>: Clone,
+{
+    let mut map: Vec<&(IPR, IPR)> = map.iter().collect();
+    map.sort_by_key(|(old, _)| std::cmp::Reverse(old.as_ref().len()));
+
+    let remap = |path: &str| -> Option<String> {
+        for (old, new) in &map {
+            let rest = match path.strip_prefix(old.as_ref()) {
+                Some("") => "",
+                Some(rest) => match rest.strip_prefix('/') {
+                    Some(rest) => rest,
+                    None => continue,
+                },
+                None => continue,
+            };
+            return match (new.as_ref(), rest) {
+                ("", "") => None,
+                ("", rest) => Some(rest.to_owned()),
+                (new, "") => Some(new.to_owned()),
+                (new, rest) => Some(format!("{}/{}", new, rest)),
+            };
+        }
+        Some(path.to_owned())
+    };
+    Ark::from_entries(
+        ark.to_entries()
+            .into_iter()
+            .filter_map(|(p, a, c)| Some((IPR::from(remap(p.as_ref())?), a, c))),
+    )
+}
+
+/// Marks a deletion in an `overlay` top layer, like OCI image layers do.
+///
+/// An entry named `.wh.foo` in the top layer removes `foo` (and everything
+/// under it, if it's a dir) from the base. The marker itself isn't kept.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Layer `top` over `base`, as in a container image.
+///
+/// Entries in `top` win, including dir attrs. Anything in `base` that `top`
+/// doesn't mention survives, even under a dir that `top` redefines. If `top`
+/// puts a file where `base` had a dir, the dir's contents go too.
+pub fn overlay<C>(base: Ark<C>, top: Ark<C>) -> Ark<C>
+where
+    C: Clone,
+{
+    let mut whiteouts: HashSet<String> = HashSet::new();
+    let mut top_files: HashSet<String> = HashSet::new();
+    let mut top_entries = vec![];
+    for (ipr, attrs, contents) in top.to_entries() {
+        let path = ipr.as_ref();
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (Some(parent), name),
+            None => (None, path),
+        };
+        if let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) {
+            whiteouts.insert(match parent {
+                Some(parent) => format!("{}/{}", parent, target),
+                None => target.to_owned(),
+            });
+            continue;
+        }
+        if let Contents::File(_) = contents {
+            top_files.insert(path.to_owned());
+        }
+        top_entries.push((ipr, attrs, contents));
+    }
+
+    let hidden = |path: &str| {
+        if whiteouts.contains(path) {
+            return true;
+        }
+        path.match_indices('/').any(|(n, _)| {
+            let ancestor = &path[..n];
+            whiteouts.contains(ancestor) || top_files.contains(ancestor)
+        })
+    };
+    let kept = base.filter(|ipr, _| !hidden(ipr.as_ref())).to_entries();
+    Ark::from_entries(kept.into_iter().chain(top_entries))
+}
+
+/// Read a CAS blob, blaming a specific path if it's missing.
+fn read_blob(db: &DB, digest: &Digest, ipr: &IPR) -> Result<Vec<u8>> {
+    std::fs::read(db.cas_path(digest)).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => DirtabaseError::DigestNotFound {
+            digest: *digest,
+            path: Some(ipr.clone()),
+        }
+        .into(),
+        _ => e,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_tools::Rng;
+
+    #[test]
+    fn translate() {
+        let ark = Ark::from_entries([
+            ("dir", Contents::Dir),
+            ("dir/a", Contents::File("one")),
+            ("dir/b", Contents::File("three")),
+        ]);
+        let lens = ark.clone().translate(|_, c| c.len());
+        assert_eq!(lens.paths(), ark.paths());
+        assert_eq!(lens.attrs(), ark.attrs());
+        assert_eq!(lens.contents(), &vec![3, 5]);
+    }
+
+    #[test]
+    fn try_translate_err() {
+        let ark = Ark::from_entries([("a", Contents::File(1)), ("b", Contents::File(2))]);
+        let res: Result<Ark<()>> =
+            ark.try_translate(|ipr, _| Err(Error::other(format!("Failed at {}", ipr.as_ref()))));
+        assert_eq!(res.unwrap_err().to_string(), "Failed at a");
+    }
+
+    #[test]
+    fn iter() -> Result<()> {
+        let ark = Ark::scan("fixture")?;
+        let borrowed: Vec<_> = ark
+            .iter()
+            .map(|(p, a, c)| (p.clone(), a.clone(), c.is_file()))
+            .collect();
+        let owned: Vec<_> = ark
+            .clone()
+            .to_entries()
+            .into_iter()
+            .map(|(p, a, c)| (p, a, c.is_file()))
+            .collect();
+        assert_eq!(borrowed, owned);
+        assert_eq!(
+            ark.iter().nth(1).map(|(_, _, c)| c),
+            Some(Contents::File(&"fixture/file_at_root.txt".into()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lens() -> Result<()> {
+        let ark = Ark::scan("fixture")?;
+        assert_eq!((ark.len_files(), ark.len_dirs()), (2, 2));
+        assert!(!ark.is_empty());
+
+        let empty = Ark::<()>::empty();
+        assert_eq!((empty.len_files(), empty.len_dirs()), (0, 0));
+        assert!(empty.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn empty_digest() -> Result<()> {
+        assert_eq!(
+            Ark::<Digest>::empty().to_json()?.to_digest(),
+            super::empty_digest()
+        );
+        assert_eq!(
+            Ark::<&str>::empty().to_json()?.to_digest(),
+            super::empty_digest()
+        );
+        let db = DB::new_temp()?;
+        assert_eq!(Ark::<Digest>::empty().store(&db)?, super::empty_digest());
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_attrs() {
+        let ark = Ark::from_entries([
+            ("dir", at! { UNIX_UID => "1000", N => "1" }, Contents::Dir),
+            (
+                "dir/a",
+                at! { UNIX_UID => "1000", N => "2" },
+                Contents::File("a"),
+            ),
+        ]);
+        let mut seen = vec![];
+        let rewritten = ark.clone().rewrite_attrs(|ipr, a| {
+            seen.push(ipr.clone());
+            a.delete("UNIX_UID")
+        });
+
+        assert_eq!(seen, vec![IPR::from("dir/a"), IPR::from("dir")]);
+        // Shared, not copied
+        assert!(std::ptr::eq(rewritten.paths(), ark.paths()));
+        assert!(std::ptr::eq(rewritten.contents(), ark.contents()));
+        assert_eq!(rewritten.attrs(), &vec![at! { N => "2" }, at! { N => "1" }]);
+    }
+
+    #[test]
+    fn get() {
+        let ark = Ark::from_entries([
+            ("src", Contents::Dir),
+            ("src/lib.rs", Contents::File("lib")),
+            ("src/main.rs", Contents::File("main")),
+            ("zzz", Contents::Dir),
+        ]);
+        assert_eq!(ark.get("src/main.rs"), Some(Contents::File(&"main")));
+        assert_eq!(ark.get("src/lib.rs"), Some(Contents::File(&"lib")));
+        assert_eq!(ark.get("src"), Some(Contents::Dir));
+        assert_eq!(ark.get("zzz"), Some(Contents::Dir));
+        assert_eq!(ark.get("src/nope.rs"), None);
+    }
+
+    #[test]
+    fn at_index() {
+        let ark = Ark::from_entries([
+            ("src", at! { N => "1" }, Contents::Dir),
+            ("src/lib.rs", at! { N => "2" }, Contents::File("lib")),
+            ("src/main.rs", at! { N => "3" }, Contents::File("main")),
+        ]);
+        let main = IPR::from("src/main.rs");
+        assert_eq!(ark.file_at(0).map(|f| f.2), Some(&"lib"));
+        assert_eq!(ark.file_at(1), Some((&main, &at! { N => "3" }, &"main")));
+        assert_eq!(ark.file_at(2), None);
+
+        let src = IPR::from("src");
+        assert_eq!(ark.dir_at(0), Some((&src, &at! { N => "1" })));
+        assert_eq!(ark.dir_at(1), None);
+        assert_eq!(ark.dir_at(usize::MAX), None);
+    }
+
+    #[test]
+    fn flatten() {
+        let ark = Ark::from_entries([
+            ("lua", at! { N => "1" }, Contents::Dir),
+            ("lua/src", at! { N => "2" }, Contents::Dir),
+            ("lua/src/lua.c", at! { N => "3" }, Contents::File("code")),
+            ("lua/README", at! { N => "4" }, Contents::File("docs")),
+        ]);
+        let flat = ark.flatten();
+        assert_eq!(flat.paths(), &vec!["README", "src/lua.c", "src"]);
+        assert_eq!(
+            flat.attrs(),
+            &vec![at! { N => "4" }, at! { N => "3" }, at! { N => "2" }]
+        );
+        assert_eq!(flat.contents(), &vec!["docs", "code"]);
+    }
+
+    #[test]
+    fn flatten_noop() {
+        let cases = [
+            Ark::empty(),
+            Ark::from_entries([("a/x", Contents::File("x")), ("b/y", Contents::File("y"))]),
+            Ark::from_entries([("lonely.txt", Contents::File("file"))]),
+            Ark::from_entries([("empty", Contents::Dir)]),
+        ];
+        for ark in cases {
+            assert_eq!(ark.clone().flatten(), ark);
+        }
+    }
+
+    #[test]
+    fn filter() -> Result<()> {
+        let ark = Ark::from_entries([
+            ("a", at! { K => "1" }, Contents::Dir),
+            ("a/x", at! { K => "1" }, Contents::File("x")),
+            ("a/y", at! {}, Contents::File("y")),
+            ("b", at! {}, Contents::Dir),
+            ("z", at! { K => "1" }, Contents::File("z")),
+        ]);
+        let kept = ark.clone().filter(|_, a| a.get("K").is_some());
+        kept.validate()?;
+        assert_eq!(kept.paths(), &vec!["a/x", "z", "a"]);
+        assert_eq!(kept.contents(), &vec!["x", "z"]);
+
+        let kept = ark.clone().filter(|p, _| p.as_ref().starts_with('a'));
+        assert_eq!(kept.paths(), &vec!["a/x", "a/y", "a"]);
+        assert_eq!(kept.contents(), &vec!["x", "y"]);
+        assert!(ark.filter(|_, _| false).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_round_trip() -> Result<()> {
+        let ark = Ark::from_entries([
+            ("b.txt", at! { N => "1" }, Contents::File("b")),
+            ("b", at! { N => "2" }, Contents::Dir),
+            ("b/c", at! { N => "3" }, Contents::File("c")),
+        ]);
+        let prefixed = ark.clone().prefix("x/y");
+        assert_eq!(prefixed.paths(), &vec!["x/y/b.txt", "x/y/b/c", "x/y/b"]);
+        assert_eq!(prefixed.contents(), ark.contents());
+        assert!(prefixed.validate().is_ok());
+        assert_eq!(prefixed.strip_prefix("x/y")?, ark);
+        assert_eq!(ark.clone().prefix(""), ark);
+        Ok(())
+    }
+
+    #[test]
+    fn strip_prefix() -> Result<()> {
+        let ark = Ark::from_entries([
+            ("x", Contents::Dir),
+            ("x/a", Contents::File("a")),
+            ("x/b", Contents::Dir),
+        ]);
+        assert_eq!(ark.strip_prefix("x")?.paths(), &vec!["a", "b"]);
+
+        let outside = Ark::from_entries([("x/a", Contents::File("a")), ("xy", Contents::Dir)]);
+        let err = outside.strip_prefix("x").expect_err("xy isn't under x");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let file = Ark::from_entries([("x", Contents::File("x"))]);
+        assert!(file.strip_prefix("x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reproducible() {
+        let ark = Ark::from_entries([
+            ("dir", at! { UNIX_MODE => "16893" }, Contents::Dir), // 0o40775
+            (
+                "dir/run.sh",
+                at! { X => "1", UNIX_MODE => "33252" },
+                Contents::File("x"),
+            ), // 0o100744
+            (
+                "dir/data",
+                at! { UNIX_MODE => "33200" },
+                Contents::File("y"),
+            ), // 0o100660
+            (
+                "odd",
+                at! { UNIX_MODE => "not a number" },
+                Contents::File("z"),
+            ),
+        ]);
+        assert_eq!(
+            ark.reproducible().attrs(),
+            &vec![
+                at! { UNIX_MODE => "33188" },           // 0o100644
+                at! { UNIX_MODE => "33261", X => "1" }, // 0o100755
+                at! { UNIX_MODE => "not a number" },
+                at! { UNIX_MODE => "16877" }, // 0o40755
+            ]
+        );
+    }
+
+    /// (path, X attr, UNIX_MODE, file contents), kept plain so it can be
+    /// copied. See `to_entries` for the real thing.
+    type MessyEntry = (&'static str, usize, &'static str, Option<u32>);
+
+    /// Lots of small archives full of duplicate paths, from a fixed seed.
+    fn messy_entries() -> Vec<Vec<MessyEntry>> {
+        let paths = ["a", "a/b", "a/b/c", "d", "d/e"];
+        let modes = ["33188", "33252", "16893", "16877"];
+        let mut rng = Rng::new(0x2545_f491);
+        let mut next = |n: usize| rng.below(n);
+        (0..200)
+            .map(|_| {
+                (0..next(8))
+                    .map(|_| {
+                        let path = paths[next(paths.len())];
+                        let mode = modes[next(modes.len())];
+                        (
+                            path,
+                            next(3),
+                            mode,
+                            Some(next(100) as u32).filter(|c| c % 2 == 0),
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn to_entries(messy: &[MessyEntry]) -> Vec<(&'static str, Attrs, Contents<u32>)> {
+        messy
+            .iter()
+            .map(|(path, x, mode, contents)| {
+                let attrs = Attrs::new()
+                    .append("X", x.to_string())
+                    .append("UNIX_MODE", *mode);
+                (*path, attrs, Contents::from_option(*contents))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn normalize_idempotent() {
+        for messy in messy_entries() {
+            let ark = Ark::from_entries(to_entries(&messy));
+            assert_eq!(Ark::from_entries(ark.clone().to_entries()), ark);
+
+            // Dedup is last-wins by the original order, whatever the hashing.
+            let mut last = messy.clone();
+            last.reverse();
+            let mut seen = HashSet::new();
+            last.retain(|(p, _, _, _)| seen.insert(*p));
+            assert_eq!(Ark::from_entries(to_entries(&last)), ark);
+
+            let once = ark.reproducible();
+            assert_eq!(once.clone().reproducible(), once);
+        }
+    }
+
+    #[test]
+    fn tag_mime() {
+        let ark = Ark::from_entries([
+            ("site", at! {}, Contents::Dir),
+            ("site/index.html", at! {}, Contents::File("<html>")),
+            ("site/data.bin", at! {}, Contents::File("?")),
+            (
+                "site/feed",
+                at! { MIME => "application/rss+xml" },
+                Contents::File("<rss>"),
+            ),
+        ]);
+        assert_eq!(
+            ark.tag_mime().attrs(),
+            &vec![
+                at! { MIME => "application/octet-stream" },
+                at! { MIME => "application/rss+xml" },
+                at! { MIME => "text/html" },
+                at! {},
+            ]
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let cases: [(&str, Option<InvalidArk>); 5] = [
+            (r#"[["a","b"],[[],[]],["x"]]"#, None),
+            (
+                r#"[["a","b"],[[]],["x"]]"#,
+                Some(InvalidArk::Lengths {
+                    paths: 2,
+                    attrs: 1,
+                    contents: 1,
+                }),
+            ),
+            (
+                r#"[["b","a"],[[],[]],["x","y"]]"#,
+                Some(InvalidArk::Unsorted("a".into())),
+            ),
+            (
+                r#"[["a","./a"],[[],[]],["x","y"]]"#,
+                Some(InvalidArk::Unsorted("a".into())),
+            ),
+            (
+                r#"[["a","a"],[[],[]],["x"]]"#,
+                Some(InvalidArk::FileAndDir("a".into())),
+            ),
+        ];
+        for (json, expected) in cases {
+            let ark: Ark<String> = serde_json::from_str(json).expect("Valid JSON");
+            assert_eq!(ark.validate().err(), expected, "{}", json);
+        }
+    }
+
+    #[test]
+    fn from_json_fuzz() -> Result<()> {
+        let db = DB::new_temp()?;
+        let json = Ark::scan("fixture")?.import_files(&db)?.to_json()?;
+        let json = json.as_bytes();
+        let ark: Ark<Digest> = Ark::from_json(json)?;
+        assert_eq!(ark.len(), 4);
+
+        let mut mangled: Vec<Vec<u8>> = (0..json.len()).map(|n| json[..n].to_vec()).collect();
+        for pos in 0..json.len() {
+            for b in *b"[]{}\",0x" {
+                let mut m = json.to_vec();
+                m[pos] = b;
+                mangled.push(m);
+            }
+        }
+        for m in mangled {
+            // Whatever gets through has to be safe to use
+            if let Ok(ark) = Ark::<Digest>::from_json(&m) {
+                for (ipr, _, _) in ark.iter() {
+                    assert!(ark.get(ipr.clone()).is_some());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remap_prefixes() {
+        let ark = Ark::from_entries([
+            ("src", Contents::Dir),
+            ("src/a.c", Contents::File("a")),
+            ("src/gen", Contents::Dir),
+            ("src/gen/b.c", Contents::File("b")),
+            ("srcs/c.c", Contents::File("c")),
+            ("README", Contents::File("r")),
+        ]);
+        let map: Vec<(IPR, IPR)> = [("src", "code"), ("src/gen", ""), ("README", "docs/README")]
+            .into_iter()
+            .map(|(old, new)| (old.into(), new.into()))
+            .collect();
+        let remapped = super::remap_prefixes(ark, &map);
+        assert_eq!(
+            remapped.paths(),
+            &vec!["b.c", "code/a.c", "docs/README", "srcs/c.c", "code"]
+        );
+        assert_eq!(remapped.contents(), &vec!["b", "a", "r", "c"]);
+    }
+
+    #[test]
+    fn overlay_layers() {
+        let base = Ark::from_entries([
+            ("etc", at! { UNIX_MODE => "16877" }, Contents::Dir),
+            ("etc/hosts", at! {}, Contents::File("base hosts")),
+            ("etc/passwd", at! {}, Contents::File("root")),
+            ("opt", at! {}, Contents::Dir),
+            ("opt/tool", at! {}, Contents::Dir),
+            ("opt/tool/bin", at! {}, Contents::File("old tool")),
+            ("var", at! {}, Contents::Dir),
+            ("var/cache", at! {}, Contents::File("stale")),
+            ("var/lib", at! {}, Contents::Dir),
+            ("var/lib/db", at! {}, Contents::File("data")),
+        ]);
+        let top = Ark::from_entries([
+            ("etc", at! { UNIX_MODE => "16832" }, Contents::Dir),
+            ("etc/hosts", at! {}, Contents::File("top hosts")),
+            ("opt/.wh.tool", at! {}, Contents::File("")),
+            ("var/.wh.cache", at! {}, Contents::File("")),
+            ("var/lib", at! {}, Contents::File("now a file")),
+        ]);
+        assert_eq!(
+            overlay(base, top),
+            Ark::from_entries([
+                ("etc", at! { UNIX_MODE => "16832" }, Contents::Dir),
+                ("etc/hosts", at! {}, Contents::File("top hosts")),
+                ("etc/passwd", at! {}, Contents::File("root")),
+                ("opt", at! {}, Contents::Dir),
+                ("var", at! {}, Contents::Dir),
+                ("var/lib", at! {}, Contents::File("now a file")),
+            ])
+        );
+    }
+
+    #[test]
+    fn overlay_whiteout_at_root() {
+        let base = Ark::from_entries([
+            ("keep", Contents::File("k")),
+            ("gone", Contents::Dir),
+            ("gone/deep", Contents::File("d")),
+            ("gone-ish", Contents::File("g")),
+        ]);
+        let top = Ark::from_entries([(".wh.gone", Contents::File(""))]);
+        assert_eq!(
+            overlay(base, top),
+            Ark::from_entries([
+                ("keep", Contents::File("k")),
+                ("gone-ish", Contents::File("g"))
+            ])
+        );
+    }
+
+    fn conflicting() -> Vec<Ark<&'static str>> {
+        vec![
+            Ark::from_entries([
+                (
+                    "dir",
+                    at! { UNIX_MODE => "16877", OWNER => "base" },
+                    Contents::Dir,
+                ),
+                ("dir/base.txt", Attrs::new(), Contents::File("base")),
+            ]),
+            Ark::from_entries([
+                ("dir", at! { OWNER => "top" }, Contents::Dir),
+                ("dir/top.txt", Attrs::new(), Contents::File("top")),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn merge_last_wins() {
+        let ark = merge(conflicting(), MergeStrategy::LastWins);
+        assert_eq!(ark.paths(), &vec!["dir/base.txt", "dir/top.txt", "dir"]);
+        assert_eq!(ark.attrs()[2], at! { OWNER => "top" });
+    }
+
+    #[test]
+    fn merge_first_wins() {
+        let ark = merge(conflicting(), MergeStrategy::FirstWins);
+        assert_eq!(ark.paths(), &vec!["dir/base.txt", "dir/top.txt", "dir"]);
+        assert_eq!(
+            ark.attrs()[2],
+            at! { UNIX_MODE => "16877", OWNER => "base" }
+        );
+    }
+
+    #[test]
+    fn merge_attrs() {
+        let ark = merge(conflicting(), MergeStrategy::MergeAttrs);
+        assert_eq!(ark.paths(), &vec!["dir/base.txt", "dir/top.txt", "dir"]);
+        assert_eq!(ark.attrs()[2], at! { UNIX_MODE => "16877", OWNER => "top" });
+    }
+
+    #[test]
+    fn read() -> Result<()> {
+        let db = DB::new_temp()?;
+        let digest = Ark::scan("fixture")?.import(&db)?;
+        let ark: Ark<Digest> = Ark::load(&db, &digest)?;
+        assert_eq!(ark.read(&db)?, Ark::scan("fixture")?.read()?);
+        Ok(())
+    }
+
+    #[test]
+    fn read_missing_blob() -> Result<()> {
+        let db = DB::new_temp()?;
+        let ark: Ark<Digest> = Ark::scan("fixture")?.import_files(&db)?;
+        let missing = ark.contents()[1];
+        std::fs::remove_file(db.join("cas").join(missing.to_hex()))?;
+
+        let err = ark.read(&db).expect_err("Blob should be missing");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err.to_string().contains("file_at_root.txt"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn load_all() -> Result<()> {
+        let db = DB::new_temp()?;
+        let digests: Vec<Digest> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|name| {
+                Ark::from_entries([(*name, Contents::File(Digest::from(*name)))]).store(&db)
+            })
+            .collect::<Result<_>>()?;
+        for threads in [1, 4] {
+            let arks = super::load_all(&db, &digests, threads)?;
+            for (ark, digest) in arks.iter().zip(&digests) {
+                assert_eq!(ark, &Ark::load(&db, digest)?);
+            }
+        }
+
+        let missing = Digest::from("missing");
+        let err = super::load_all(&db, &[digests[0], missing], 4).expect_err("Missing");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn load_checked() -> Result<()> {
+        let db = DB::new_temp()?;
+        let digest = Ark::scan("fixture")?.import(&db)?;
+        let ark: Ark<Digest> = Ark::load_checked(&db, &digest)?;
+        assert_eq!(ark, Ark::load(&db, &digest)?);
+        Ok(())
+    }
+
+    #[test]
+    fn store() -> Result<()> {
+        let db = DB::new_temp()?;
+        let ark: Ark<Digest> = Ark::scan("fixture")?.import_files(&db)?;
+        let digest = ark.store(&db)?;
+        assert_eq!(digest, ark.save(&db)?);
+
+        // Backdate the blob, so a rewrite would show.
+        let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(db.cas_path(&digest), old)?;
+        assert_eq!(ark.store(&db)?, digest);
+        let meta = std::fs::metadata(db.cas_path(&digest))?;
+        assert_eq!(filetime::FileTime::from_last_modification_time(&meta), old);
+        Ok(())
+    }
+
+    #[test]
+    fn store_after_interrupted_write() -> Result<()> {
+        let db = DB::new_temp()?;
+        let ark: Ark<Digest> = Ark::scan("fixture")?.import_files(&db)?;
+        let json = ark.to_json()?;
+        let digest = Digest::from(&json);
+
+        // What a store killed halfway through leaves behind.
+        let leftover = db.tempdir()?.into_path();
+        std::fs::write(leftover.join(digest.to_hex()), &json[..json.len() / 2])?;
+        assert!(!db.exists(&digest));
+
+        assert_eq!(ark.store(&db)?, digest);
+        assert_eq!(Ark::load_checked(&db, &digest)?, ark);
+        Ok(())
+    }
+
+    #[test]
+    fn load_checked_wrong_format() -> Result<()> {
+        let db = DB::new_temp()?;
+        let cases: [(&[u8], &str); 4] = [
+            (&[0xa1, 0x61, 0x61, 0x01], "looks like CBOR"), // {"a": 1}
+            (&[0xff, 0xfe, 0x00], "looks like binary data"),
+            (b"hello", "looks like plain text"),
+            (b"{\"a\": 1}", "isn't a valid archive"),
+        ];
+        for (bytes, expected) in cases {
+            let digest = Digest::from(bytes);
+            std::fs::write(db.cas_path(&digest), bytes)?;
+            let err = Ark::load_checked(&db, &digest).expect_err("Should not decode");
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use arkive::*;
+use std::io::{Error, ErrorKind};
+
+/// Extra behavior for `Attrs`, which lives upstream in arkive.
+pub trait AttrsExt {
+    /// Sort by name, keeping the relative order of repeated names.
+    ///
+    /// Attrs compare (and serialize) in insertion order, so the same set of
+    /// attrs built in a different order would give a different archive
+    /// digest. Canonicalizing first avoids that.
+    fn canonicalize(self) -> Attrs;
+
+    /// Overlay `other` on top of self.
+    ///
+    /// Every name in `other` replaces all values of that name in self. Names
+    /// only in self are kept. Repeated names in `other` stay repeated.
+    fn merge(self, other: &Attrs) -> Attrs;
+
+    /// Names whose values differ between self and `other`, sorted.
+    ///
+    /// Covers names that only one side has. Order of values only matters
+    /// within a repeated name.
+    fn diff(&self, other: &Attrs) -> Vec<String>;
+
+    /// First value for a name, if there is one.
+    fn get(&self, name: &str) -> Option<&str>;
+
+    /// Append a binary value, hex-encoded.
+    ///
+    /// Attr values are strings on the wire, so this is how to carry things
+    /// like raw xattrs. Nothing marks the value as binary - whoever reads it
+    /// back needs to know to use `get_bytes`.
+    fn append_bytes(self, name: impl Into<String>, value: impl AsRef<[u8]>) -> Attrs;
+
+    /// Decode the first value for a name that was stored with `append_bytes`.
+    fn get_bytes(&self, name: &str) -> Option<Result<Vec<u8>, hex::FromHexError>>;
+
+    /// Like `append`, but refuse names that would break text formats.
+    ///
+    /// Plain `append` takes anything, for compatibility. See `check_name`.
+    fn try_append(
+        self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<Attrs, InvalidAttrName>;
+
+    /// `UNIX_MODE`, file type bits and all, like `st_mode`.
+    ///
+    /// It's stored as a decimal string. Missing is `None`, but a value that
+    /// isn't a number is an `InvalidData` error.
+    fn unix_mode(&self) -> Result<Option<u32>, Error>;
+
+    /// Replace `UNIX_MODE`, in the same format `unix_mode` reads.
+    fn set_unix_mode(self, mode: u32) -> Attrs;
+
+    /// Does `UNIX_MODE` have any execute bit set? False if it's missing or
+    /// can't be read.
+    fn is_executable(&self) -> bool;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InvalidAttrName {
+    Empty,
+    /// Has a colon or a control character, like a newline.
+    Malformed(String),
+}
+impl From<InvalidAttrName> for Error {
+    fn from(ian: InvalidAttrName) -> Self {
+        Self::new(
+            ErrorKind::InvalidInput,
+            match ian {
+                InvalidAttrName::Empty => "Attr names can't be empty".to_owned(),
+                InvalidAttrName::Malformed(name) => {
+                    format!(
+                        "Attr name {:?} can't contain colons or control characters",
+                        name
+                    )
+                }
+            },
+        )
+    }
+}
+
+/// Is this safe to use as an attr name?
+///
+/// Debug output is `name: value` lines, so a colon or newline in a name makes
+/// it ambiguous, and an empty name can't be told apart from a missing one.
+pub fn check_name(name: &str) -> Result<(), InvalidAttrName> {
+    if name.is_empty() {
+        Err(InvalidAttrName::Empty)
+    } else if name.contains(|c: char| c == ':' || c.is_control()) {
+        Err(InvalidAttrName::Malformed(name.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+impl AttrsExt for Attrs {
+    fn canonicalize(self) -> Attrs {
+        let mut items = self.items().clone();
+        items.sort_by(|a, b| a.name().cmp(b.name()));
+        from_items(items)
+    }
+
+    fn merge(self, other: &Attrs) -> Attrs {
+        let names = other.items().iter().map(|attr| attr.name());
+        let base = names.fold(self, |acc, name| acc.delete(name));
+        other
+            .items()
+            .iter()
+            .fold(base, |acc, attr| acc.append(attr.name(), attr.value()))
+    }
+
+    fn diff(&self, other: &Attrs) -> Vec<String> {
+        let values = |attrs: &Attrs, name: &str| -> Vec<String> {
+            attrs
+                .items()
+                .iter()
+                .filter(|attr| attr.name() == name)
+                .map(|attr| attr.value().to_owned())
+                .collect()
+        };
+        let mut names: Vec<String> = self
+            .items()
+            .iter()
+            .chain(other.items())
+            .map(|attr| attr.name().to_owned())
+            .collect();
+        names.sort();
+        names.dedup();
+        names.retain(|name| values(self, name) != values(other, name));
+        names
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.items()
+            .iter()
+            .find(|attr| attr.name() == name)
+            .map(|attr| attr.value())
+    }
+
+    fn append_bytes(self, name: impl Into<String>, value: impl AsRef<[u8]>) -> Attrs {
+        self.append(name, hex::encode(value))
+    }
+
+    fn get_bytes(&self, name: &str) -> Option<Result<Vec<u8>, hex::FromHexError>> {
+        self.get(name).map(hex::decode)
+    }
+
+    fn try_append(
+        self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<Attrs, InvalidAttrName> {
+        let name = name.into();
+        check_name(&name)?;
+        Ok(self.append(name, value))
+    }
+
+    fn unix_mode(&self) -> Result<Option<u32>, Error> {
+        self.get(UNIX_MODE)
+            .map(|v| {
+                v.parse().map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Bad {} value: {:?}", UNIX_MODE, v),
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    fn set_unix_mode(self, mode: u32) -> Attrs {
+        self.set(UNIX_MODE, mode.to_string())
+    }
+
+    fn is_executable(&self) -> bool {
+        matches!(self.unix_mode(), Ok(Some(mode)) if mode & 0o111 != 0)
+    }
+}
+
+const UNIX_MODE: &str = "UNIX_MODE";
+
+fn from_items(items: impl IntoIterator<Item = Attr>) -> Attrs {
+    items.into_iter().fold(Attrs::new(), |acc, attr| {
+        acc.append(attr.name(), attr.value())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn canonicalize() {
+        let a = at! { UNIX_MODE => "33204", B => "1", A => "x", B => "2" };
+        let b = at! { A => "x", B => "1", UNIX_MODE => "33204", B => "2" };
+        assert_ne!(a, b);
+        assert_eq!(a.clone().canonicalize(), b.canonicalize());
+        assert_eq!(
+            a.canonicalize(),
+            at! { A => "x", B => "1", B => "2", UNIX_MODE => "33204" }
+        );
+    }
+
+    #[test]
+    fn unix_mode() {
+        let attrs = Attrs::new().set_unix_mode(0o100755);
+        assert_eq!(attrs, at! { UNIX_MODE => "33261" });
+        assert_eq!(attrs.unix_mode().unwrap(), Some(0o100755));
+        assert!(attrs.is_executable());
+
+        let attrs = attrs.set_unix_mode(0o100644);
+        assert_eq!(attrs.items().len(), 1);
+        assert_eq!(attrs.unix_mode().unwrap(), Some(0o100644));
+        assert!(!attrs.is_executable());
+
+        for mode in [0, 0o40755, 0o120777, u32::MAX] {
+            assert_eq!(
+                Attrs::new().set_unix_mode(mode).unix_mode().unwrap(),
+                Some(mode)
+            );
+        }
+
+        assert_eq!(Attrs::new().unix_mode().unwrap(), None);
+        assert!(!Attrs::new().is_executable());
+        let bad = at! { UNIX_MODE => "rwxr-xr-x" };
+        assert_eq!(bad.unix_mode().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!bad.is_executable());
+    }
+
+    #[test]
+    fn merge() {
+        let base = at! { A => "1", B => "2", B => "3" };
+        let over = at! { B => "4", C => "5", C => "6" };
+        assert_eq!(
+            base.merge(&over),
+            at! { A => "1", B => "4", C => "5", C => "6" }
+        );
+    }
+
+    #[test]
+    fn diff() {
+        let a = at! { A => "1", B => "2", D => "x", D => "y" };
+        let b = at! { C => "3", B => "2", A => "changed", D => "y", D => "x" };
+        assert_eq!(a.diff(&b), vec!["A", "C", "D"]);
+        assert_eq!(a.diff(&a.clone().canonicalize()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn bytes() {
+        let raw: &[u8] = &[0, 159, 146, 150, 255];
+        let attrs = at! { UNIX_MODE => "33188" }.append_bytes("XATTR_USER_TAG", raw);
+        assert_eq!(attrs.get("XATTR_USER_TAG"), Some("009f9296ff"));
+        assert_eq!(attrs.get_bytes("XATTR_USER_TAG"), Some(Ok(raw.to_vec())));
+        assert_eq!(attrs.get_bytes("MISSING"), None);
+        assert!(attrs.get_bytes("UNIX_MODE").unwrap().is_err());
+
+        // Still plain strings on the wire
+        let json = serde_json::to_string(&attrs).unwrap();
+        assert_eq!(
+            json,
+            r#"[["UNIX_MODE","33188"],["XATTR_USER_TAG","009f9296ff"]]"#
+        );
+    }
+
+    #[test]
+    fn try_append() {
+        let attrs = Attrs::new().try_append("UNIX_MODE", "33188").unwrap();
+        assert_eq!(attrs, at! { UNIX_MODE => "33188" });
+        assert_eq!(
+            attrs.clone().try_append("", "x"),
+            Err(InvalidAttrName::Empty)
+        );
+        for bad in ["a\nb", "a:b", "tab\there"] {
+            assert_eq!(
+                attrs.clone().try_append(bad, "x"),
+                Err(InvalidAttrName::Malformed(bad.to_owned()))
+            );
+        }
+    }
+}
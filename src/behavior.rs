@@ -1,31 +1,70 @@
+use crate::ark::{load_all, merge, remap_prefixes, ArkExt, MergeStrategy, StoredArk};
+use crate::attrs::AttrsExt;
 use crate::context::Context;
+use crate::db::DBExt;
+use crate::digest::DigestExt;
+use crate::error::DirtabaseError;
+use crate::export;
+use crate::glob;
+use crate::import::{is_small, ImportExt};
+use crate::label::{self, Label};
 use crate::op::Op;
+use crate::pool;
+use crate::scan::{scan_with_warnings, ScanOptions};
+use crate::sign;
 use arkive::*;
-use std::io::{Error, Result, Write};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::path::Path;
 
-// Todo: move into prefix op
-fn prefix_ark<C>(ark: Ark<C>, prefix: &str) -> Ark<C> {
-    let (p, a, c) = ark.decompose();
-    let p: Vec<IPR> = p
-        .iter()
-        .map(|ipr| (prefix.to_owned() + "/" + ipr.as_ref()).to_ipr())
-        .collect();
-    Ark::compose(std::rc::Rc::new(p), a, c)
-}
+/// How many files to import between progress updates.
+const PROGRESS_EVERY: usize = 100;
 
 /// Download a file and save it to the store.
-fn download(db: &DB, url: &str) -> Result<Digest> {
-    // TODO: db.tempdir()
-    let dir = tempfile::tempdir_in(db.join("tmp"))?;
-    let mut resp = reqwest::blocking::get(url).map_err(|e| Error::other(e))?;
+///
+/// With a `limit`, a response bigger than that many bytes is an error, and
+/// nothing from it reaches the store.
+fn download(db: &DB, url: &str, limit: Option<u64>) -> Result<Digest> {
+    let dir = db.tempdir()?;
+    let failed = |source| DirtabaseError::Download {
+        url: url.to_owned(),
+        source,
+    };
+    let mut resp = reqwest::blocking::get(url).map_err(failed)?;
     let name = url_filename(url)?;
     let dest = dir.path().join(name);
-    resp.copy_to(&mut std::fs::File::create(dest)?)
-        .map_err(|e| Error::other(e))?;
+    let mut file = std::fs::File::create(dest)?;
+    match limit {
+        Some(limit) => {
+            copy_capped(&mut resp, &mut file, limit).map_err(|e| match e.kind() {
+                ErrorKind::FileTooLarge => DirtabaseError::DownloadTooLarge {
+                    url: url.to_owned(),
+                    limit,
+                }
+                .into(),
+                _ => e,
+            })?;
+        }
+        None => {
+            resp.copy_to(&mut file).map_err(failed)?;
+        }
+    }
     Ark::scan(dir.path())?.import(db)
 }
 
+/// Like `io::copy`, but give up with `FileTooLarge` past `limit` bytes.
+fn copy_capped(src: &mut impl Read, dest: &mut impl Write, limit: u64) -> Result<u64> {
+    let copied = std::io::copy(&mut src.take(limit + 1), dest)?;
+    if copied > limit {
+        return Err(Error::new(
+            ErrorKind::FileTooLarge,
+            format!("More than {} bytes", limit),
+        ));
+    }
+    Ok(copied)
+}
+
 /// Derive a filename from parsing a URL.
 pub fn url_filename(given_url: &str) -> Result<String> {
     let parsed_url = reqwest::Url::parse(&given_url).map_err(|e| Error::other(e))?;
@@ -38,152 +77,482 @@ pub fn url_filename(given_url: &str) -> Result<String> {
 }
 
 /// Run a command in a way that includes your normal shell environment
-pub fn command(ctx: &mut Context, digest: &Digest, cmd: &str) -> Result<Digest> {
+///
+/// With `outputs`, only paths matching those globs are reimported, see
+/// `glob::keep_matching`.
+pub fn command(
+    ctx: &mut Context,
+    digest: &Digest,
+    cmd: &str,
+    outputs: &[String],
+) -> Result<Digest> {
     // Extract to temporary directory
     let dir = tempfile::tempdir()?;
-    let ark: Ark<Digest> = Ark::load(ctx.db, digest)?;
+    let ark: Ark<Digest> = Ark::load_checked(ctx.db, digest)?;
     ark.write(ctx.db, dir.path())?;
 
     // Run the command
     // Equivalent to: bash -o pipefail -e -c '...'
     write!(ctx.log.cmd(), "--- [{}] ---\n", cmd)?;
-    let status = std::process::Command::new("bash")
+    let mut command = std::process::Command::new("bash");
+    command
         .arg("-o")
         .arg("pipefail")
         .arg("-e")
         .arg("-c")
         .arg(cmd)
-        .current_dir(&dir)
-        .status()?;
+        .current_dir(&dir);
+    if ctx.config.json {
+        // Stdout is reserved for JSON
+        command.stdout(std::io::stderr());
+    }
+    let status = command.status()?;
 
     if !&status.success() {
-        return Err(Error::other(format!(
-            "Command {:?} failed with status {:?}",
-            cmd,
-            status.code().unwrap()
-        )));
+        return Err(DirtabaseError::CommandFailed {
+            cmd: cmd.to_owned(),
+            code: status.code(),
+        }
+        .into());
     }
 
     // Re-import directory back into a new stored archive
-    Ark::scan(dir.path())?.import(ctx.db)
+    let mut result = Ark::scan(dir.path())?;
+    if !outputs.is_empty() {
+        result = glob::keep_matching(result, outputs)?;
+    }
+    result.import(ctx.db)
+}
+
+/// Write a manifest of what an export just wrote, if `--manifest` asked for one.
+fn write_manifest(ctx: &Context, ark: &Ark<Digest>) -> Result<()> {
+    let Some(path) = &ctx.config.manifest else {
+        return Ok(());
+    };
+    let path = match &ctx.config.jail {
+        Some(root) => export::jailed(root, path)?,
+        None => path.clone(),
+    };
+    export::write_manifest(ark, path)
+}
+
+/// Do a path-only op (see `Op::is_path_only`) to an archive in memory.
+fn rewrite_paths(ark: Ark<Digest>, op: &Op) -> Result<Ark<Digest>> {
+    let keep_matching = |ark: Ark<Digest>, res: &[regex::Regex]| {
+        ark.filter(|p, _| res.iter().any(|re| re.is_match(p.as_ref())))
+    };
+    Ok(match op {
+        Op::Prefix(prefix) => ark.prefix(prefix.as_str()),
+        Op::Rename(pattern, replacement) | Op::RenameAll(pattern, replacement) => {
+            let re = regex::Regex::new(pattern).map_err(DirtabaseError::InvalidPattern)?;
+            let limit = match op {
+                Op::RenameAll(_, _) => 0,
+                _ => 1,
+            };
+            let entries: Vec<(IPR, Attrs, Contents<Digest>)> = ark
+                .to_entries()
+                .into_iter()
+                .map(|(p, a, c)| {
+                    let renamed = re.replacen(p.as_ref(), limit, replacement);
+                    (renamed.as_ref().to_ipr(), a, c)
+                })
+                .collect();
+            Ark::from_entries(entries)
+        }
+        Op::Filter(pattern) => {
+            let re = regex::Regex::new(pattern).map_err(DirtabaseError::InvalidPattern)?;
+            keep_matching(ark, &[re])
+        }
+        Op::Flatten => ark.flatten(),
+        Op::PrefixMap(map) => {
+            let map: Vec<(IPR, IPR)> = map
+                .iter()
+                .map(|(old, new)| (old.as_str().into(), new.as_str().into()))
+                .collect();
+            remap_prefixes(ark, &map)
+        }
+        _ => {
+            return Err(Error::other(format!(
+                "{:?} isn't a path-only op",
+                op.to_code()
+            )))
+        }
+    })
+}
+
+/// One line of `--list` output. Dirs have no size or digest.
+#[derive(Serialize)]
+struct ListEntry<'a> {
+    path: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: Option<u64>,
+    mode: Option<u32>,
+    digest: Option<String>,
+}
+
+/// Files that share contents, grouped by digest, for `--dedup`.
+///
+/// Only groups of 2 or more count. Paths within a group are in archive
+/// order, and groups are ordered by their first path.
+fn duplicates(ark: &Ark<Digest>) -> Vec<(Digest, Vec<String>)> {
+    // Digest isn't Hash upstream, but its bytes are.
+    let mut groups: HashMap<&[u8], (Digest, Vec<String>)> = HashMap::new();
+    for (ipr, _, digest) in ark.files() {
+        groups
+            .entry(digest.to_bytes())
+            .or_insert_with(|| (*digest, vec![]))
+            .1
+            .push(ipr.as_ref().to_owned());
+    }
+    let mut groups: Vec<_> = groups
+        .into_values()
+        .filter(|(_, paths)| paths.len() > 1)
+        .collect();
+    groups.sort_by(|a, b| a.1[0].cmp(&b.1[0]));
+    groups
+}
+
+/// The one archive an op that takes one was given.
+///
+/// `ReadyStep` already pops the right number, so this only fails if
+/// `exec_step` was called some other way. That's still not worth a panic.
+fn single(op: &Op, consumed: &[Digest]) -> Result<Digest> {
+    match consumed {
+        [digest] => Ok(*digest),
+        _ => Err(Error::other(format!(
+            "{:?} takes 1 archive off the stack, but was given {}",
+            op.to_code(),
+            consumed.len()
+        ))),
+    }
 }
 
 pub fn exec_step(ctx: &mut Context, op: &Op, consumed: &Vec<Digest>) -> Result<()> {
     Ok(match op {
         Op::Empty => {
-            ctx.push(Ark::<&str>::empty().save(ctx.db)?);
+            ctx.push(Ark::<Digest>::empty().store(ctx.db)?);
         }
         Op::Import { base, targets } => {
+            // Already expanded, see `glob::expand_imports`.
             for target in targets {
-                let real_dir = Path::new(&base).join(target);
-                let ark = prefix_ark(Ark::scan(real_dir)?, target);
-                ctx.push(ark.import(ctx.db)?);
+                let real = Path::new(&base).join(target);
+                // A dir's contents go under the target, a file sits where the
+                // target says it is.
+                let prefix = if real.is_dir() {
+                    target.as_str()
+                } else {
+                    Path::new(target)
+                        .parent()
+                        .and_then(|p| p.to_str())
+                        .unwrap_or("")
+                };
+                let opts = ScanOptions {
+                    prefix: prefix.to_owned(),
+                    ..ctx.config.scan.clone()
+                };
+                let log = &mut ctx.log;
+                let mut ark = scan_with_warnings(&real, &opts, |w| {
+                    let _ = writeln!(log.warning(), "{}", w);
+                })?;
+                if ctx.config.mime {
+                    ark = ark.tag_mime();
+                }
+                if ctx.config.reproducible {
+                    ark = ark.reproducible();
+                }
+                let db = ctx.db;
+                let threads = ctx.config.threads();
+                let log = &mut ctx.log;
+                let progress = |done, total| {
+                    if done % PROGRESS_EVERY == 0 || done == total {
+                        // Progress is nice to have, not worth failing over.
+                        let _ = writeln!(log.progress(), "imported {}/{} files", done, total);
+                    }
+                };
+                let digest = if is_small(&ark) {
+                    ark.read()?.import_with_progress(db, threads, progress)?
+                } else {
+                    ark.import_with_progress(db, threads, progress)?
+                };
+                ctx.push(digest);
             }
         }
         Op::Export(base) => {
-            assert_eq!(consumed.len(), 1, "Export consumes 1 archive off the stack");
-            let base = Path::new(base);
-            let parent = base.parent().ok_or_else(|| {
-                std::io::Error::other("Can't create tempdir to the side of output location")
+            let input = single(op, consumed)?;
+            let base = match &ctx.config.jail {
+                Some(root) => export::jailed(root, base)?,
+                None => base.into(),
+            };
+            let log = &mut ctx.log;
+            let ark = export::export(ctx.db, &input, base, |w| {
+                let _ = writeln!(log.warning(), "{}", w);
             })?;
-
-            let digest = consumed[0];
-            let ark: Ark<Digest> = Ark::load(ctx.db, &digest)?;
-            let tmp = tempfile::tempdir_in(parent)?;
-            ark.write(ctx.db, &tmp)?;
-
-            if base.exists() {
-                std::fs::rename(&base, parent.join(".tmp-remove-me"))?;
-                std::fs::rename(&tmp, &base)?;
-                std::fs::remove_dir_all(parent.join(".tmp-remove-me"))?;
-            } else {
-                std::fs::rename(&tmp, &base)?;
-            }
+            write_manifest(ctx, &ark)?;
         }
-        Op::Merge => {
-            let arks: Result<Vec<Ark<Digest>>> = consumed
-                .iter()
-                .map(|digest| Ark::load(ctx.db, digest))
-                .collect();
-
-            let ark = Ark::from_entries(
-                arks?
-                    .into_iter()
-                    .flat_map(|ark| ark.to_entries())
-                    .collect::<Vec<(IPR, Attrs, Contents<Digest>)>>(),
-            );
-            ctx.push(ark.save(ctx.db)?);
-        }
-        Op::Prefix(prefix) => {
-            assert_eq!(consumed.len(), 1, "Prefix consumes 1 archive off the stack");
-            let digest = consumed[0];
-            let ark: Ark<Digest> = Ark::load(ctx.db, &digest)?;
-
-            let prefix = prefix.to_ipr();
-            let (p, a, c) = ark.decompose();
-            let p: Vec<IPR> = p
-                .iter()
-                .map(|path| {
-                    let combined = prefix.as_ref().to_owned() + "/" + path.as_ref();
-                    combined.into()
-                })
-                .collect();
-            let ark = Ark::compose(std::rc::Rc::new(p), a, c);
-            ctx.push(ark.save(ctx.db)?)
+        Op::Merge(_, strategy) => {
+            let arks = load_all(ctx.db, consumed, ctx.config.threads())?;
+            let ark = merge(arks, *strategy);
+            ctx.push(ark.store(ctx.db)?);
         }
-        Op::Rename(pattern, replacement) => {
-            assert_eq!(consumed.len(), 1, "Rename consumes 1 archive off the stack");
-            let digest = consumed[0];
-            let ark: Ark<Digest> = Ark::load(ctx.db, &digest)?;
-
-            let re = regex::Regex::new(pattern).map_err(|e| Error::other(e))?;
-            let entries: Vec<(IPR, Attrs, Contents<Digest>)> = ark
-                .to_entries()
-                .into_iter()
-                .map(|(p, a, c)| (re.replace(p.as_ref(), replacement).as_ref().to_ipr(), a, c))
-                .collect();
-
-            let ark = Ark::from_entries(entries);
-            ctx.push(ark.save(ctx.db)?)
+        Op::Prefix(_)
+        | Op::Filter(_)
+        | Op::Rename(_, _)
+        | Op::RenameAll(_, _)
+        | Op::Flatten
+        | Op::PrefixMap(_) => {
+            let input = single(op, consumed)?;
+            let ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+            ctx.push(rewrite_paths(ark, op)?.store(ctx.db)?)
         }
-        Op::Filter(pattern) => {
-            assert_eq!(consumed.len(), 1, "Filter consumes 1 archive off the stack");
-            let digest = consumed[0];
-            let ark: Ark<Digest> = Ark::load(ctx.db, &digest)?;
-
-            let re = regex::Regex::new(pattern).map_err(|e| Error::other(e))?;
-            let entries: Vec<(IPR, Attrs, Contents<Digest>)> = ark
-                .to_entries()
-                .into_iter()
-                .filter(|(p, _, _)| re.is_match(p.as_ref()))
-                .collect();
-            let ark = Ark::from_entries(entries);
-            ctx.push(ark.save(ctx.db)?)
+        Op::Fused(ops) => {
+            let input = single(op, consumed)?;
+            let mut ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+            for op in ops {
+                ark = rewrite_paths(ark, op)?;
+            }
+            ctx.push(ark.store(ctx.db)?)
         }
         Op::Download(url, digest_expected) => {
-            let digest = download(ctx.db, &url)?;
+            let digest = download(ctx.db, &url, ctx.config.max_download)?;
             if digest != *digest_expected {
-                return Err(Error::other(format!(
-                    "Hash check failed. Expected {}, got {}",
-                    digest_expected.to_hex(),
-                    digest.to_hex()
-                )));
+                return Err(DirtabaseError::HashMismatch {
+                    expected: *digest_expected,
+                    actual: digest,
+                }
+                .into());
             }
             ctx.push(digest);
         }
         Op::DownloadImpure(url) => {
-            ctx.push(download(ctx.db, &url)?);
-        }
-        Op::CmdImpure(cmd) => {
-            assert_eq!(
-                consumed.len(),
-                1,
-                "CmdImpure consumes 1 archive off the stack"
-            );
-            let digest = consumed[0];
-            let produced = command(ctx, &digest, &cmd)?;
+            ctx.push(download(ctx.db, &url, ctx.config.max_download)?);
+        }
+        // Any --inputs were already applied, see `ReadyStep::narrow`.
+        Op::CmdImpure { cmd, outputs, .. } | Op::CmdCached { cmd, outputs, .. } => {
+            let digest = single(op, consumed)?;
+            let produced = command(ctx, &digest, cmd, outputs)?;
             ctx.push(produced);
         }
+        Op::ExportMerge(dest) => {
+            let input = single(op, consumed)?;
+            let dest = match &ctx.config.jail {
+                Some(root) => export::jailed(root, dest)?,
+                None => dest.into(),
+            };
+            let ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+            export::merge_into(ctx.db, &ark, &dest, ctx.config.threads())?;
+            let log = &mut ctx.log;
+            export::restore_metadata(&ark, &dest, |w| {
+                let _ = writeln!(log.warning(), "{}", w);
+            })?;
+            write_manifest(ctx, &ark)?;
+        }
+        Op::File(path, content) => {
+            let input = single(op, consumed)?;
+            let ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+
+            let path = path.to_ipr();
+            if path.as_ref().is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "File path is empty"));
+            }
+            if let Some(Contents::Dir) = ark.get(path.clone()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Can't write file {:?} over a directory", path),
+                ));
+            }
+
+            // rw-r--r--, same as reproducible() would give it
+            let attrs = Attrs::new().set_unix_mode(0o100644);
+            let file =
+                Ark::from_entries([(path, attrs, Contents::File(content.as_bytes().to_vec()))])
+                    .import_files_with_progress(ctx.db, 1, |_, _| {})?;
+            // Not `overlay`, which would read a `.wh.` name as a whiteout.
+            ctx.push(merge([ark, file], MergeStrategy::LastWins).store(ctx.db)?)
+        }
+        Op::Verify => {
+            let input = single(op, consumed)?;
+            let ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+
+            // Keep going past the first bad blob, so the whole extent of the
+            // damage shows up in one run.
+            let db = ctx.db;
+            let files: Vec<_> = ark.files().collect();
+            let mut bad = vec![];
+            pool::run(
+                ctx.config.threads(),
+                &files,
+                |_, (_, _, digest)| match db.verify(digest) {
+                    Ok(true) => Ok(None),
+                    Ok(false) => Ok(Some("corrupt")),
+                    Err(e) if e.kind() == ErrorKind::NotFound => Ok(Some("missing")),
+                    Err(e) => Err(e),
+                },
+                |n, problem| {
+                    if let Some(problem) = problem {
+                        bad.push(format!("{} ({})", files[n].0.as_ref(), problem));
+                    }
+                },
+            )?;
+            if !bad.is_empty() {
+                bad.sort();
+                return Err(DirtabaseError::Corrupt {
+                    total: files.len(),
+                    problems: bad,
+                }
+                .into());
+            }
+            ctx.push(input)
+        }
+        Op::Tag(name) => {
+            let input = single(op, consumed)?;
+            Label::new(name)?.write(ctx.db, &input)?;
+            ctx.push(input)
+        }
+        Op::Extract(path) => {
+            let input = single(op, consumed)?;
+            let ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+            let ipr = IPR::from(path.as_str());
+            let digest = match ark.get(ipr.clone()) {
+                Some(Contents::File(digest)) => *digest,
+                Some(Contents::Dir) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Can't extract {:?}, it's a directory", path),
+                    ))
+                }
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("Can't extract {:?}, it's not in the archive", path),
+                    ))
+                }
+            };
+
+            // Stream it, raw. Whatever's on the other end can deal with binary.
+            let mut blob =
+                std::fs::File::open(ctx.db.cas_path(&digest)).map_err(|e| match e.kind() {
+                    ErrorKind::NotFound => DirtabaseError::DigestNotFound {
+                        digest,
+                        path: Some(ipr),
+                    }
+                    .into(),
+                    _ => e,
+                })?;
+            std::io::copy(&mut blob, &mut ctx.log.stdout)?;
+            ctx.log.stdout.flush()?;
+            ctx.push(input)
+        }
+        Op::Assert(expected) => {
+            let input = single(op, consumed)?;
+            if input != *expected {
+                return Err(DirtabaseError::HashMismatch {
+                    expected: *expected,
+                    actual: input,
+                }
+                .into());
+            }
+            ctx.push(input)
+        }
+        Op::Sign(keyfile) => {
+            let input = single(op, consumed)?;
+            let key = sign::signing_key(keyfile)?;
+            sign::sign(ctx.db, &input, &key)?;
+            writeln!(
+                ctx.log.progress(),
+                "signed {} with public key {}",
+                input.short(),
+                hex::encode(key.verifying_key().to_bytes())
+            )?;
+            ctx.push(input)
+        }
+        Op::VerifySig(pubkey) => {
+            let input = single(op, consumed)?;
+            sign::verify(ctx.db, &input, &sign::verifying_key(pubkey)?)?;
+            ctx.push(input)
+        }
+        Op::Dedup => {
+            let input = single(op, consumed)?;
+            let ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+            let mut reclaimable = 0;
+            let groups = duplicates(&ark);
+            for (digest, paths) in &groups {
+                let size = std::fs::metadata(ctx.db.cas_path(digest))?.len();
+                let wasted = size * (paths.len() as u64 - 1);
+                reclaimable += wasted;
+                if ctx.config.json {
+                    let line = serde_json::json!({
+                        "digest": digest.to_hex(),
+                        "size": size,
+                        "paths": paths,
+                    });
+                    writeln!(ctx.log.stdout, "{}", line)?;
+                } else {
+                    writeln!(
+                        ctx.log.stdout,
+                        "{} copies of {} ({} bytes each, {} reclaimable):",
+                        paths.len(),
+                        digest.short(),
+                        size,
+                        wasted
+                    )?;
+                    for path in paths {
+                        writeln!(ctx.log.stdout, "  {}", path)?;
+                    }
+                }
+            }
+            if !ctx.config.json {
+                writeln!(
+                    ctx.log.stdout,
+                    "{} bytes reclaimable across {} groups of duplicates",
+                    reclaimable,
+                    groups.len()
+                )?;
+            }
+            ctx.push(input)
+        }
+        Op::List => {
+            let input = single(op, consumed)?;
+            let ark: Ark<Digest> = Ark::load_checked(ctx.db, &input)?;
+            for (n, (ipr, attrs)) in ark.paths().iter().zip(ark.attrs().iter()).enumerate() {
+                let digest = ark.contents().get(n);
+                let size = match digest {
+                    Some(digest) => Some(std::fs::metadata(ctx.db.cas_path(digest))?.len()),
+                    None => None,
+                };
+                let entry = ListEntry {
+                    path: ipr.as_ref(),
+                    kind: if digest.is_some() { "file" } else { "dir" },
+                    size,
+                    mode: attrs.unix_mode()?,
+                    digest: digest.map(|d| d.to_hex()),
+                };
+                writeln!(ctx.log.stdout, "{}", serde_json::to_string(&entry)?)?;
+            }
+            ctx.push(input)
+        }
+        Op::Print => {
+            for digest in &ctx.stack {
+                writeln!(ctx.log.stdout, "{}", digest.to_hex())?;
+            }
+        }
+        Op::Labels => {
+            for label in label::list(ctx.db)? {
+                // Could vanish between listing and reading, no big deal.
+                let Some(digest) = label.read(ctx.db)? else {
+                    continue;
+                };
+                if ctx.config.json {
+                    let line = serde_json::json!({
+                        "label": label.name(),
+                        "digest": digest.to_hex(),
+                    });
+                    writeln!(ctx.log.stdout, "{}", line)?;
+                } else {
+                    writeln!(ctx.log.stdout, "{} -> {}", label.name(), digest.to_hex())?;
+                }
+            }
+        }
     })
 }
 
@@ -199,13 +568,14 @@ impl Context<'_> {
         T: Into<Vec<S>>,
         S: AsRef<str>,
     {
+        let targets: Vec<String> = targets
+            .into()
+            .iter()
+            .map(|s| s.as_ref().to_owned())
+            .collect();
         self.apply(&Op::Import {
+            targets: glob::expand_all(base.as_ref(), &targets)?,
             base: base.as_ref().into(),
-            targets: targets
-                .into()
-                .iter()
-                .map(|s| s.as_ref().to_owned())
-                .collect(),
         })?;
         Ok(self)
     }
@@ -215,8 +585,23 @@ impl Context<'_> {
         Ok(self)
     }
 
+    pub fn export_merge(&mut self, dest: impl AsRef<str>) -> Result<&mut Self> {
+        self.apply(&Op::ExportMerge(dest.as_ref().to_owned()))?;
+        Ok(self)
+    }
+
     pub fn merge(&mut self) -> Result<&mut Self> {
-        self.apply(&Op::Merge)?;
+        self.apply(&Op::Merge(None, MergeStrategy::LastWins))?;
+        Ok(self)
+    }
+
+    pub fn merge_top(&mut self, n: usize) -> Result<&mut Self> {
+        self.apply(&Op::Merge(Some(n), MergeStrategy::LastWins))?;
+        Ok(self)
+    }
+
+    pub fn merge_with(&mut self, n: Option<usize>, strategy: MergeStrategy) -> Result<&mut Self> {
+        self.apply(&Op::Merge(n, strategy))?;
         Ok(self)
     }
 
@@ -225,6 +610,15 @@ impl Context<'_> {
         Ok(self)
     }
 
+    pub fn prefix_map<S: AsRef<str>>(&mut self, map: &[(S, S)]) -> Result<&mut Self> {
+        let map = map
+            .iter()
+            .map(|(old, new)| (old.as_ref().to_owned(), new.as_ref().to_owned()))
+            .collect();
+        self.apply(&Op::PrefixMap(map))?;
+        Ok(self)
+    }
+
     pub fn filter(&mut self, pattern: impl AsRef<str>) -> Result<&mut Self> {
         self.apply(&Op::Filter(pattern.as_ref().to_owned()))?;
         Ok(self)
@@ -242,6 +636,18 @@ impl Context<'_> {
         Ok(self)
     }
 
+    pub fn rename_all(
+        &mut self,
+        pattern: impl AsRef<str>,
+        replacement: impl AsRef<str>,
+    ) -> Result<&mut Self> {
+        self.apply(&Op::RenameAll(
+            pattern.as_ref().to_owned(),
+            replacement.as_ref().to_owned(),
+        ))?;
+        Ok(self)
+    }
+
     pub fn download(&mut self, url: impl AsRef<str>, hash: impl AsRef<str>) -> Result<&mut Self> {
         let digest = Digest::from_hex(hash.as_ref())
             .map_err(|e| crate::op::ParseError::InvalidDigest(hash.as_ref().to_owned(), e))?;
@@ -249,13 +655,114 @@ impl Context<'_> {
         Ok(self)
     }
 
+    pub fn assert(&mut self, hash: impl AsRef<str>) -> Result<&mut Self> {
+        let digest = Digest::from_hex(hash.as_ref())
+            .map_err(|e| crate::op::ParseError::InvalidDigest(hash.as_ref().to_owned(), e))?;
+        self.apply(&Op::Assert(digest))?;
+        Ok(self)
+    }
+
+    pub fn sign(&mut self, keyfile: impl AsRef<str>) -> Result<&mut Self> {
+        self.apply(&Op::Sign(keyfile.as_ref().to_owned()))?;
+        Ok(self)
+    }
+
+    pub fn verify_sig(&mut self, pubkey: impl AsRef<str>) -> Result<&mut Self> {
+        self.apply(&Op::VerifySig(pubkey.as_ref().to_owned()))?;
+        Ok(self)
+    }
+
+    pub fn dedup(&mut self) -> Result<&mut Self> {
+        self.apply(&Op::Dedup)?;
+        Ok(self)
+    }
+
+    pub fn list(&mut self) -> Result<&mut Self> {
+        self.apply(&Op::List)?;
+        Ok(self)
+    }
+
     pub fn download_impure(&mut self, url: impl AsRef<str>) -> Result<&mut Self> {
         self.apply(&Op::DownloadImpure(url.as_ref().to_owned()))?;
         Ok(self)
     }
 
     pub fn cmd_impure(&mut self, cmd: impl AsRef<str>) -> Result<&mut Self> {
-        self.apply(&Op::CmdImpure(cmd.as_ref().to_owned()))?;
+        self.cmd_impure_with(cmd, &[], &[])
+    }
+
+    pub fn cmd_impure_with(
+        &mut self,
+        cmd: impl AsRef<str>,
+        inputs: &[&str],
+        outputs: &[&str],
+    ) -> Result<&mut Self> {
+        self.apply(&Op::CmdImpure {
+            cmd: cmd.as_ref().to_owned(),
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: outputs.iter().map(|s| s.to_string()).collect(),
+        })?;
+        Ok(self)
+    }
+
+    pub fn cmd_cached(&mut self, cmd: impl AsRef<str>) -> Result<&mut Self> {
+        self.cmd_cached_with(cmd, &[], &[])
+    }
+
+    pub fn cmd_cached_with(
+        &mut self,
+        cmd: impl AsRef<str>,
+        inputs: &[&str],
+        outputs: &[&str],
+    ) -> Result<&mut Self> {
+        self.apply(&Op::CmdCached {
+            cmd: cmd.as_ref().to_owned(),
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: outputs.iter().map(|s| s.to_string()).collect(),
+        })?;
+        Ok(self)
+    }
+
+    pub fn file(&mut self, path: impl AsRef<str>, content: impl AsRef<str>) -> Result<&mut Self> {
+        self.apply(&Op::File(
+            path.as_ref().to_owned(),
+            content.as_ref().to_owned(),
+        ))?;
+        Ok(self)
+    }
+
+    pub fn verify(&mut self) -> Result<&mut Self> {
+        self.apply(&Op::Verify)?;
+        Ok(self)
+    }
+
+    pub fn tag(&mut self, name: impl AsRef<str>) -> Result<&mut Self> {
+        self.apply(&Op::Tag(name.as_ref().to_owned()))?;
+        Ok(self)
+    }
+
+    pub fn print(&mut self) -> Result<&mut Self> {
+        self.apply(&Op::Print)?;
+        Ok(self)
+    }
+
+    pub fn labels(&mut self) -> Result<&mut Self> {
+        self.apply(&Op::Labels)?;
+        Ok(self)
+    }
+
+    pub fn extract(&mut self, path: impl AsRef<str>) -> Result<&mut Self> {
+        self.apply(&Op::Extract(path.as_ref().to_owned()))?;
+        Ok(self)
+    }
+
+    pub fn flatten(&mut self) -> Result<&mut Self> {
+        self.apply(&Op::Flatten)?;
+        Ok(self)
+    }
+
+    pub fn fused(&mut self, ops: impl Into<Vec<Op>>) -> Result<&mut Self> {
+        self.apply(&Op::Fused(ops.into()))?;
         Ok(self)
     }
 }
@@ -263,8 +770,9 @@ impl Context<'_> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::ark::empty_digest;
     use crate::logger::Logger;
-    use crate::test_tools::fixture_digest;
+    use crate::test_tools::{fixture_digest, Rng};
 
     #[test]
     fn empty() -> std::io::Result<()> {
@@ -272,7 +780,245 @@ mod test {
         let mut log = Logger::new_vec();
         let mut ctx = Context::new(&db, &mut log);
         exec_step(&mut ctx, &Op::Empty, &vec![])?;
-        assert_eq!(ctx.stack, vec![Ark::<&str>::empty().to_json()?.to_digest()]);
+        assert_eq!(ctx.stack, vec![empty_digest()]);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_capped() -> std::io::Result<()> {
+        let mut out = vec![];
+        assert_eq!(super::copy_capped(&mut &b"12345"[..], &mut out, 5)?, 5);
+        assert_eq!(out, b"12345");
+
+        let err =
+            super::copy_capped(&mut &b"123456"[..], &mut vec![], 5).expect_err("One byte too many");
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+        Ok(())
+    }
+
+    #[test]
+    fn file() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.import(".", ["fixture"])?
+            .file("./fixture//new.txt", "hello")?
+            .file("fixture/file_at_root.txt", "replaced")?;
+
+        let ark: Ark<Digest> = Ark::load(&db, &ctx.stack[0])?;
+        assert_eq!(ark.len(), 5);
+        assert_eq!(
+            ark.get("fixture/new.txt"),
+            Some(Contents::File(&Digest::from("hello")))
+        );
+        assert_eq!(
+            ark.get("fixture/file_at_root.txt"),
+            Some(Contents::File(&Digest::from("replaced")))
+        );
+        assert!(db.cas_path(&Digest::from("hello")).exists());
+
+        // Just a file, not a whiteout of its neighbour.
+        ctx.file("fixture/.wh.file_at_root.txt", "not a marker")?;
+        let ark: Ark<Digest> = Ark::load(&db, &ctx.stack[0])?;
+        assert_eq!(ark.len(), 6);
+        assert!(ark.get("fixture/file_at_root.txt").is_some());
+        assert_eq!(
+            ark.get("fixture/.wh.file_at_root.txt"),
+            Some(Contents::File(&Digest::from("not a marker")))
+        );
+
+        let Err(err) = ctx.file("fixture/dir1", "oops") else {
+            panic!("Dir in the way")
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let Err(err) = ctx.empty()?.file("/", "oops") else {
+            panic!("No path")
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn export_jailed() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let jail = tempfile::tempdir()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.config.jail = Some(jail.path().into());
+
+        let Err(err) = ctx.empty()?.export("../../etc/passwd") else {
+            panic!("Outside the jail")
+        };
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        let inside = jail.path().join("out");
+        ctx.import(".", ["fixture"])?
+            .export(inside.to_str().unwrap())?;
+        assert!(inside.join("fixture/file_at_root.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_top() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.empty()?.import(".", ["fixture", "src", "fixture"])?;
+        let bottom = ctx.stack[..2].to_vec();
+
+        ctx.merge_top(2)?;
+        assert_eq!(ctx.stack.len(), 3);
+        assert_eq!(ctx.stack[..2], bottom);
+        let ark: Ark<Digest> = Ark::load(&db, &ctx.stack[2])?;
+        assert!(ark.get("src/lib.rs").is_some());
+        assert!(ark.get("fixture/file_at_root.txt").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn extract() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        log.pol.opheader = crate::logger::Policy::Silent;
+        log.pol.stack = crate::logger::Policy::Silent;
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.import(".", ["fixture"])?
+            .extract("fixture/file_at_root.txt")?;
+        assert_eq!(ctx.stack.len(), 1);
+
+        let Err(err) = ctx.extract("fixture/dir1") else {
+            panic!("Is a dir")
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let Err(err) = ctx.import(".", ["fixture"])?.extract("nope") else {
+            panic!("Missing")
+        };
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        assert_eq!(log.recorded().0, "Here are some file contents, teehee!\n");
+        Ok(())
+    }
+
+    #[test]
+    fn rename_first_or_all() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.empty()?.file("lib/lib/lib.a", "")?;
+        let input = ctx.stack[0];
+        let paths = |ctx: &Context| -> std::io::Result<Vec<IPR>> {
+            let ark: Ark<Digest> = Ark::load(ctx.db, &ctx.stack[0])?;
+            Ok(ark.paths().to_vec())
+        };
+
+        ctx.rename("lib", "src")?;
+        assert_eq!(paths(&ctx)?, vec!["src/lib/lib.a"]);
+        ctx.stack = vec![input];
+        ctx.rename_all("lib", "src")?;
+        assert_eq!(paths(&ctx)?, vec!["src/src/src.a"]);
+        ctx.stack = vec![input];
+        ctx.rename_all(r"(\w+)\.a$", "${1}_static.a")?;
+        assert_eq!(paths(&ctx)?, vec!["lib/lib/lib_static.a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn fused_matches_unfused() -> std::io::Result<()> {
+        let choices = [
+            Op::Prefix("a".into()),
+            Op::Prefix("b/c".into()),
+            Op::Filter("root".into()),
+            Op::Filter("^a/".into()),
+            Op::Rename("dir".into(), "d".into()),
+            Op::Rename("^".into(), "x/".into()),
+            Op::RenameAll("i".into(), "I".into()),
+            Op::Flatten,
+            Op::PrefixMap(vec![("fixture".into(), "f".into())]),
+            Op::PrefixMap(vec![("a".into(), "".into())]),
+        ];
+        let mut rng = Rng::new(0x9e37_79b9);
+        let mut next = |n: usize| rng.below(n);
+
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.config.no_cache = true;
+        ctx.import(".", ["fixture"])?;
+        let input = ctx.stack[0];
+        for _ in 0..100 {
+            let ops: Vec<Op> = (0..2 + next(5))
+                .map(|_| choices[next(choices.len())].clone())
+                .collect();
+            ctx.stack = vec![input];
+            ops.iter().try_for_each(|op| ctx.apply(op))?;
+            let unfused = ctx.stack.clone();
+
+            ctx.stack = vec![input];
+            ctx.fused(ops.clone())?;
+            assert_eq!(ctx.stack, unfused, "{:?}", ops);
+        }
+
+        // Only path-only ops can be fused, anything else is an error.
+        let Err(err) = ctx.fused([Op::Flatten, Op::Verify]) else {
+            panic!("Verify isn't path-only")
+        };
+        assert_eq!(err.to_string(), "Verify isn't a path-only op");
+        Ok(())
+    }
+
+    #[test]
+    fn assert() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        let fixture = fixture_digest().to_hex();
+        ctx.import(".", ["fixture"])?.assert(&fixture)?;
+        assert_eq!(ctx.stack, vec![fixture_digest()]);
+
+        let Err(err) = ctx.empty()?.assert(&fixture) else {
+            panic!("Wrong digest")
+        };
+        assert!(matches!(
+            DirtabaseError::find(&err),
+            Some(DirtabaseError::HashMismatch { .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Hash check failed. Expected {}, got {}",
+                fixture,
+                empty_digest().to_hex()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.import(".", ["fixture"])?.verify()?;
+        let digest = ctx.stack[0];
+
+        let ark: Ark<Digest> = Ark::load(&db, &digest)?;
+        for (_, _, blob) in ark.files() {
+            std::fs::write(db.cas_path(blob), "rotten")?;
+        }
+        let Err(err) = ctx.verify() else {
+            panic!("Both blobs are bad")
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            DirtabaseError::find(&err),
+            Some(DirtabaseError::Corrupt { total: 2, .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "2 of 2 files failed verification:\n  \
+             fixture/dir1/dir2/nested.txt (corrupt)\n  \
+             fixture/file_at_root.txt (corrupt)"
+        );
         Ok(())
     }
 
@@ -290,6 +1036,45 @@ mod test {
             &vec![],
         )?;
         assert_eq!(ctx.stack, vec![fixture_digest()]);
+        assert_eq!(log.recorded().1, "imported 2/2 files\n");
+        Ok(())
+    }
+
+    #[test]
+    fn import_glob() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+
+        // Two matches, so two archives, which the arity check knows about.
+        ctx.parse_apply(vec![
+            "--import".into(),
+            ".".into(),
+            "fixture/*".into(),
+            "--merge".into(),
+            "2".into(),
+        ])?;
+        let ark: Ark<Digest> = Ark::load(&db, &ctx.stack[0])?;
+        assert_eq!(
+            ark.paths(),
+            &vec![
+                "fixture/dir1/dir2/nested.txt",
+                "fixture/file_at_root.txt",
+                "fixture/dir1/dir2",
+            ]
+        );
+
+        let Err(err) = ctx.import(".", ["fixture/*.rs"]) else {
+            panic!("No matches")
+        };
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // Even a dry run finds out up front, rather than guessing a count.
+        ctx.config.dry_run = true;
+        let err = ctx
+            .parse_apply(vec!["--import".into(), ".".into(), "fixture/*.rs".into()])
+            .expect_err("No matches");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
         Ok(())
     }
 
@@ -309,4 +1094,342 @@ mod test {
         assert!(Path::new("out/src").exists());
         Ok(())
     }
+
+    #[test]
+    fn import_file() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.import("fixture", ["file_at_root.txt", "dir1/dir2/nested.txt"])?;
+
+        let top: Ark<Digest> = Ark::load(&db, &ctx.stack[0])?;
+        let nested: Ark<Digest> = Ark::load(&db, &ctx.stack[1])?;
+        assert_eq!(top.paths(), &vec!["file_at_root.txt"]);
+        assert_eq!(nested.paths(), &vec!["dir1/dir2/nested.txt"]);
+        assert_eq!(top.attrs()[0].items()[0].name(), "UNIX_MODE");
+        Ok(())
+    }
+
+    #[test]
+    fn import_shared_across_targets() -> std::io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let dir = tempfile::tempdir()?;
+        let big = vec![7u8; 2 * 1024 * 1024]; // Too big for is_small()
+        for target in ["a", "b"] {
+            std::fs::create_dir(dir.path().join(target))?;
+            std::fs::write(dir.path().join(target).join("vendored.bin"), &big)?;
+        }
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        let base = dir.path().to_str().expect("utf8 tempdir");
+        ctx.import(base, ["a"])?;
+
+        // Importing b, and a again alongside it, leaves the blob alone.
+        let blob = db.cas_path(&Digest::from(&big));
+        let inode = std::fs::metadata(&blob)?.ino();
+        ctx.import(base, ["a", "b"])?;
+        assert_eq!(std::fs::metadata(&blob)?.ino(), inode);
+        assert_eq!(std::fs::read_dir(db.join("tmp"))?.count(), 0);
+
+        let a: Ark<Digest> = Ark::load(&db, &ctx.stack[1])?;
+        let b: Ark<Digest> = Ark::load(&db, &ctx.stack[2])?;
+        assert_eq!(a.contents(), b.contents());
+        Ok(())
+    }
+
+    #[test]
+    fn import_same_across_dbs() -> std::io::Result<()> {
+        let digests: std::io::Result<Vec<Digest>> = (0..2)
+            .map(|_| {
+                let db = DB::new_temp()?;
+                let mut log = Logger::new_vec();
+                let mut ctx = Context::new(&db, &mut log);
+                ctx.import(".", ["fixture"])?;
+                Ok(ctx.stack[0])
+            })
+            .collect();
+        assert_eq!(digests?, vec![fixture_digest(), fixture_digest()]);
+        Ok(())
+    }
+
+    #[test]
+    fn import_reproducible() -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Same tree, checked out under different umasks
+        let trees: std::io::Result<Vec<tempfile::TempDir>> = [0o664, 0o644]
+            .into_iter()
+            .map(|mode| {
+                let dir = tempfile::tempdir()?;
+                std::fs::create_dir(dir.path().join("t"))?;
+                let file = dir.path().join("t/file.txt");
+                std::fs::write(&file, "same")?;
+                std::fs::set_permissions(&file, std::fs::Permissions::from_mode(mode))?;
+                Ok(dir)
+            })
+            .collect();
+        let trees = trees?;
+
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        for tree in &trees {
+            ctx.import(tree.path().to_str().unwrap(), ["t"])?;
+        }
+        assert_ne!(ctx.stack[0], ctx.stack[1]);
+
+        ctx.config.reproducible = true;
+        for tree in &trees {
+            ctx.import(tree.path().to_str().unwrap(), ["t"])?;
+        }
+        assert_eq!(ctx.stack[2], ctx.stack[3]);
+        Ok(())
+    }
+
+    #[test]
+    fn rename_top_only() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.import(".", ["fixture", "fixture"])?;
+        ctx.rename("^fixture/(.*)_at_root", "moved/${1}")?;
+
+        let bottom: Ark<Digest> = Ark::load(&db, &ctx.stack[0])?;
+        let top: Ark<Digest> = Ark::load(&db, &ctx.stack[1])?;
+        assert_eq!(ctx.stack[0], fixture_digest());
+        assert!(bottom.get("fixture/file_at_root.txt").is_some());
+        assert!(top.get("moved/file.txt").is_some());
+        assert!(top.get("fixture/file_at_root.txt").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn empty_dirs_survive() -> std::io::Result<()> {
+        let src = tempfile::tempdir()?;
+        std::fs::create_dir_all(src.path().join("var/log"))?;
+        std::fs::write(src.path().join("var/log/app.log"), "noise")?;
+        let out = tempfile::tempdir()?;
+        let dest = out.path().join("out");
+
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.import(src.path().to_str().unwrap(), ["var"])?
+            .filter("^var(/log)?$")?
+            .prefix("root")?
+            .rename("/log$", "/logs")?
+            .empty()?
+            .merge()?;
+
+        let ark: Ark<Digest> = Ark::load(&db, &ctx.stack[0])?;
+        assert_eq!(ark.paths(), &vec!["root/var/logs"]);
+        assert_eq!(ark.get("root/var/logs"), Some(Contents::Dir));
+        ctx.export(dest.to_str().unwrap())?;
+        assert_eq!(std::fs::read_dir(dest.join("root/var/logs"))?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn cmd_cached() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+
+        // Not reproducible at all, so only a cache hit gives the same result.
+        let cmd = "date +%s%N > stamp";
+        ctx.empty()?.cmd_cached(cmd)?;
+        ctx.empty()?.cmd_cached(cmd)?;
+        assert_eq!(ctx.stack[0], ctx.stack[1]);
+
+        ctx.empty()?.cmd_impure(cmd)?;
+        assert_ne!(ctx.stack[0], ctx.stack[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn cmd_declared() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        let cmd = "date +%s%N > stamp && mkdir -p build && cp src/* build/";
+
+        // Only what's declared goes in or comes back out. The stamp only
+        // matches between runs if the second one came from the cache.
+        ctx.empty()?
+            .file("src/main.c", "int main;")?
+            .file("notes.txt", "v1")?
+            .cmd_cached_with(cmd, &["src/**"], &["build/**", "stamp"])?;
+        let first = ctx.stack[0];
+        let ark: Ark<Digest> = Ark::load(&db, &first)?;
+        assert_eq!(ark.paths(), &vec!["build/main.c", "stamp", "build"]);
+
+        // Changing an undeclared input is still a cache hit.
+        ctx.empty()?
+            .file("src/main.c", "int main;")?
+            .file("notes.txt", "v2")?
+            .cmd_cached_with(cmd, &["src/**"], &["build/**", "stamp"])?;
+        assert_eq!(ctx.stack[1], first);
+
+        // Unlike a declared one, or different outputs.
+        ctx.empty()?
+            .file("src/main.c", "int main() {}")?
+            .cmd_cached_with(cmd, &["src/**"], &["build/**", "stamp"])?;
+        assert_ne!(ctx.stack[2], first);
+        ctx.empty()?
+            .file("src/main.c", "int main;")?
+            .cmd_cached_with(cmd, &["src/**"], &["build/*.c", "stamp"])?;
+        assert_ne!(ctx.stack[3], first);
+        Ok(())
+    }
+
+    #[test]
+    fn export_empty_stack() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+
+        let Err(err) = ctx.export("out") else {
+            panic!("Nothing to export")
+        };
+        assert_eq!(
+            err.to_string(),
+            "Export needs 1 archives off the stack, but it only has 0"
+        );
+        let err = ctx
+            .parse_apply(vec!["--export".into(), "out".into()])
+            .expect_err("Nothing to export");
+        assert!(err.to_string().contains("(Export) would consume 1"));
+
+        // Even called directly, with the wrong inputs, it's an error.
+        for op in [Op::Export("out".into()), Op::Prefix("a".into())] {
+            let err = exec_step(&mut ctx, &op, &vec![]).expect_err("No input");
+            assert!(err.to_string().contains("was given 0"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dedup() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.empty()?
+            .file("a.txt", "hello")?
+            .file("dir/b.txt", "hello")?
+            .file("c.txt", "hello")?
+            .file("unique.txt", "other")?
+            .dedup()?;
+        assert_eq!(ctx.stack.len(), 1);
+
+        let out = log.recorded().0;
+        let report: Vec<&str> = out
+            .lines()
+            .skip_while(|l| !l.contains(" copies of "))
+            .collect();
+        assert!(report[0].starts_with("3 copies of "));
+        assert!(report[0].ends_with("(5 bytes each, 10 reclaimable):"));
+        assert_eq!(
+            report[1..5],
+            [
+                "  a.txt",
+                "  c.txt",
+                "  dir/b.txt",
+                "10 bytes reclaimable across 1 groups of duplicates"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn list() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.empty()?.file("docs/readme.txt", "hi")?.list()?;
+
+        let out = log.recorded().0;
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .filter(|l| l.starts_with('{'))
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<_>>()?;
+        assert_eq!(
+            lines,
+            vec![serde_json::json!({
+                "path": "docs/readme.txt",
+                "type": "file",
+                "size": 2,
+                "mode": 0o100644,
+                "digest": Digest::from("hi").to_hex(),
+            })]
+        );
+
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.import(".", ["fixture"])?.list()?;
+        let out = log.recorded().0;
+        let first = out.lines().find(|l| l.starts_with('{')).expect("a line");
+        assert!(first.starts_with(r#"{"path":"fixture/dir1/dir2/nested.txt","type":"file","#));
+        assert_eq!(out.matches(r#""type":"dir""#).count(), 2);
+        assert_eq!(out.matches(r#""type":"file""#).count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn tag_and_list() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.empty()?.tag("@empty")?;
+        ctx.import(".", ["fixture"])?.tag("@release-1.0")?;
+        let (empty, fixture) = (ctx.stack[0], ctx.stack[1]);
+        ctx.labels()?;
+        assert_eq!(ctx.stack, vec![empty, fixture]);
+        assert_eq!(Label::new("@release-1.0")?.read(&db)?, Some(fixture));
+
+        let out = log.recorded().0;
+        let listing: Vec<&str> = out.lines().filter(|l| l.contains(" -> ")).collect();
+        assert_eq!(
+            listing,
+            vec![
+                format!("@empty -> {}", empty.to_hex()),
+                format!("@release-1.0 -> {}", fixture.to_hex()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn typed_errors() -> std::io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+
+        let Err(err) = ctx.empty()?.cmd_impure("exit 3") else {
+            panic!("Fails")
+        };
+        assert!(matches!(
+            DirtabaseError::find(&err),
+            Some(DirtabaseError::CommandFailed { code: Some(3), .. })
+        ));
+
+        let Err(err) = ctx.empty()?.filter("(") else {
+            panic!("Bad regex")
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(
+            DirtabaseError::find(&err),
+            Some(DirtabaseError::InvalidPattern(_))
+        ));
+
+        let digest = Digest::from("never stored");
+        let err = Ark::load_checked(&db, &digest).expect_err("Not stored");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(matches!(
+            DirtabaseError::find(&err),
+            Some(DirtabaseError::DigestNotFound { path: None, .. })
+        ));
+        Ok(())
+    }
 }
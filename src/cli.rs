@@ -1,19 +1,96 @@
+use crate::config::Config;
 use crate::context::Context;
 use crate::doc::usage;
-use crate::logger::Logger;
+use crate::logger::{Logger, Policy};
+use crate::op::{parse_pipeline, Op};
+use crate::watch::{self, Watcher};
 use arkive::types::DB;
-use std::io::{Result, Write};
+use std::io::{Error, ErrorKind, Result, Write};
 use std::process::ExitCode;
 
 pub fn cli(args: Vec<String>, db: &DB, log: &mut Logger) -> Result<()> {
     if args.is_empty() {
         write!(log.stdout, "{}", usage())?;
     }
-    Context::new(db, log).parse_apply(args)
+    let (config, args) = Config::from_args(args)?;
+    if config.json {
+        // Keep stdout parseable
+        log.pol.cmd = Policy::Stderr;
+    }
+    if config.timing {
+        log.pol.timing = Policy::Stderr;
+    }
+    let pipeline = parse_pipeline(&args)?;
+    if pipeline.iter().any(Op::writes_stdout) {
+        // Leave stdout to the results, for `digest=$(dirtabase ... --print)`,
+        // `dirtabase ... --list | jq` or `dirtabase ... --extract f > f`
+        log.pol.opheader = Policy::Stderr;
+        log.pol.stack = Policy::Stderr;
+        log.pol.cmd = Policy::Stderr;
+    }
+    if let Some(path) = &config.log_file {
+        log.open_file(path)?;
+        log.pol.opheader = Policy::File;
+        log.pol.stack = Policy::File;
+        log.pol.cmd = Policy::File;
+        if config.timing {
+            log.pol.timing = Policy::File;
+        }
+    }
+    if config.watch {
+        return watch(&args, db, log, &config);
+    }
+    build(&args, db, log, &config)
+}
+
+/// Run the pipeline once.
+fn build(args: &[String], db: &DB, log: &mut Logger, config: &Config) -> Result<()> {
+    let mut ctx = Context::new(db, log);
+    ctx.config = config.clone();
+    ctx.parse_apply(args.to_vec())?;
+
+    if config.json {
+        let stack: Vec<String> = ctx.stack.iter().map(|d| d.to_hex()).collect();
+        writeln!(ctx.log.stdout, "{}", serde_json::to_string(&stack)?)?;
+    }
+    Ok(())
+}
+
+/// Build, then build again after every change to the imports, forever.
+///
+/// A failed build is only a warning here, since the next edit might fix it.
+/// Steps that didn't change come from the cache, so rebuilds are cheap.
+fn watch(args: &[String], db: &DB, log: &mut Logger, config: &Config) -> Result<()> {
+    let sources = watch::sources(&parse_pipeline(args)?);
+    if sources.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--watch needs at least one --import to watch",
+        ));
+    }
+    let mut watcher = match Watcher::events(&sources) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            writeln!(
+                log.warning(),
+                "Can't watch for events ({}), scanning instead",
+                e
+            )?;
+            Watcher::polling(sources, &config.scan)?
+        }
+    };
+    loop {
+        if let Err(e) = build(args, db, log, config) {
+            writeln!(log.warning(), "Build failed: {}", e)?;
+        }
+        // Don't count anything the build itself wrote.
+        watcher.forget()?;
+        watcher.wait()?;
+    }
 }
 
 fn infer_db() -> Result<DB> {
-    DB::new("./.dirtabase_db")
+    crate::db::open("./.dirtabase_db")
 }
 
 pub fn real_cli() -> ExitCode {
@@ -61,13 +138,182 @@ mod test {
             ================================================================
              + Can cache? false
              + Is in cache? false
-            8c958951d9f61be6a7b1ec48611710efc3d12ee71f3dc6ac34251afe4a95378e
+            8c958951d9f6
         "},
-                ""
+                "imported 2/2 files\n"
             )
         );
     }
 
+    #[test]
+    fn test_watch_nothing() {
+        let db = DB::new_temp().expect("Temp DB");
+        let mut logger = Logger::new_vec();
+        let err = cli(vec!["--watch".into(), "--empty".into()], &db, &mut logger)
+            .expect_err("No imports to watch");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(logger.recorded(), ("", ""));
+    }
+
+    #[test]
+    fn test_print() {
+        let db = DB::new_temp().expect("Temp DB");
+        let mut logger = Logger::new_vec();
+        let res = cli(
+            vec![
+                "--import".into(),
+                ".".into(),
+                "fixture".into(),
+                "--empty".into(),
+                "--print".into(),
+            ],
+            &db,
+            &mut logger,
+        );
+        assert!(res.is_ok());
+        let (stdout, stderr) = logger.recorded();
+        assert_eq!(
+            stdout,
+            "8c958951d9f61be6a7b1ec48611710efc3d12ee71f3dc6ac34251afe4a95378e\n\
+             5ae1625b488b3935122d8dd627fe575b388a5aa360378fa4407aad08baaed1e2\n"
+        );
+        assert!(stderr.contains("Import"));
+    }
+
+    #[test]
+    fn test_extract() {
+        let db = DB::new_temp().expect("Temp DB");
+        let mut logger = Logger::new_vec();
+        let res = cli(
+            vec![
+                "--import".into(),
+                ".".into(),
+                "fixture".into(),
+                "--extract".into(),
+                "fixture/file_at_root.txt".into(),
+            ],
+            &db,
+            &mut logger,
+        );
+        assert!(res.is_ok());
+        let (stdout, stderr) = logger.recorded();
+        assert_eq!(
+            stdout.as_bytes(),
+            std::fs::read("fixture/file_at_root.txt").expect("Read fixture")
+        );
+        assert!(stderr.contains("Extract"));
+    }
+
+    #[test]
+    fn test_merge_strategy() {
+        let run = |merge: &[&str]| {
+            let db = DB::new_temp().expect("Temp DB");
+            let mut logger = Logger::new_vec();
+            let args = [
+                &["--empty", "--file", "app.conf", "base"][..],
+                &["--empty", "--file", "app.conf", "override"],
+                merge,
+                &["--extract", "app.conf"],
+            ];
+            let args = args.concat().iter().map(|a| a.to_string()).collect();
+            cli(args, &db, &mut logger).expect("Pipeline ran");
+            logger.recorded().0.to_owned()
+        };
+        assert_eq!(run(&["--merge"]), "override");
+        assert_eq!(run(&["--merge", "last-wins"]), "override");
+        assert_eq!(run(&["--merge", "2", "first-wins"]), "base");
+    }
+
+    #[test]
+    fn test_flag_as_op_arg() {
+        let db = DB::new_temp().expect("Temp DB");
+        let mut logger = Logger::new_vec();
+        let args = [
+            "--empty",
+            "--file",
+            "notes.txt",
+            "--json",
+            "--extract",
+            "notes.txt",
+        ];
+        cli(
+            args.iter().map(|a| a.to_string()).collect(),
+            &db,
+            &mut logger,
+        )
+        .expect("Pipeline ran");
+        assert_eq!(logger.recorded().0, "--json");
+    }
+
+    #[test]
+    fn test_log_file() {
+        let db = DB::new_temp().expect("Temp DB");
+        let dir = tempfile::tempdir().expect("Temp dir");
+        let path = dir.path().join("ci.log");
+        let mut logger = Logger::new_vec();
+        let res = cli(
+            vec![
+                "--log-file".into(),
+                path.to_str().unwrap().into(),
+                "--empty".into(),
+            ],
+            &db,
+            &mut logger,
+        );
+
+        assert!(res.is_ok());
+        drop(logger); // Flush
+        let logged = std::fs::read_to_string(&path).expect("Log file");
+        assert!(logged.contains("\nEmpty\n"), "{}", logged);
+    }
+
+    #[test]
+    fn test_full_digests() {
+        let db = DB::new_temp().expect("Temp DB");
+        let mut logger = Logger::new_vec();
+        let res = cli(
+            vec![
+                "--full-digests".into(),
+                "--import".into(),
+                ".".into(),
+                "fixture".into(),
+            ],
+            &db,
+            &mut logger,
+        );
+
+        assert!(res.is_ok());
+        assert!(logger
+            .recorded()
+            .0
+            .ends_with("\n8c958951d9f61be6a7b1ec48611710efc3d12ee71f3dc6ac34251afe4a95378e\n"));
+    }
+
+    #[test]
+    fn test_json_stack() {
+        let db = DB::new_temp().expect("Temp DB");
+        let mut logger = Logger::new_vec();
+        let res = cli(
+            vec![
+                "--json".into(),
+                "--import".into(),
+                ".".into(),
+                "fixture".into(),
+            ],
+            &db,
+            &mut logger,
+        );
+
+        assert!(res.is_ok());
+        let lines: Vec<&str> = logger.recorded().0.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"op\":\"Import\""), "{}", lines[0]);
+        assert_eq!(
+            lines[1],
+            "[\"8c958951d9f61be6a7b1ec48611710efc3d12ee71f3dc6ac34251afe4a95378e\"]"
+        );
+    }
+
     #[test]
     fn test_pipeline_caching() {
         let db = DB::new_temp().expect("Temp DB");
@@ -95,14 +341,14 @@ mod test {
             ================================================================
              + Can cache? true
              + Is in cache? false
-            460f3d82bf451fbebd1958fe4714e2a82a6570dda19e0d6f39cd7504adca6088
+            460f3d82bf45
             ================================================================
             Download
             ================================================================
              + Can cache? true
              + Is in cache? true
-            460f3d82bf451fbebd1958fe4714e2a82a6570dda19e0d6f39cd7504adca6088
-            460f3d82bf451fbebd1958fe4714e2a82a6570dda19e0d6f39cd7504adca6088
+            460f3d82bf45
+            460f3d82bf45
         "},
                 ""
             )
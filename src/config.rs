@@ -0,0 +1,319 @@
+use crate::op::{OpCode, ParseError};
+use crate::scan::{ScanOptions, SpecialFiles};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+/// Settings that apply to a whole pipeline, rather than any single op.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Config {
+    /// Skip both reading from and writing to the step cache.
+    pub no_cache: bool,
+
+    /// Evict least recently used cache entries past this many bytes.
+    pub cache_limit: Option<u64>,
+
+    /// Fail any download bigger than this many bytes.
+    pub max_download: Option<u64>,
+
+    /// Normalize permissions and attr order on import, see `ArkExt::reproducible`.
+    pub reproducible: bool,
+
+    /// How imports read the disk, e.g. whether to record owners and mtimes.
+    pub scan: ScanOptions,
+
+    /// Tag imported files with a `MIME` attr, see `ArkExt::tag_mime`.
+    pub mime: bool,
+
+    /// Log one JSON object per step to stdout, instead of the human format.
+    pub json: bool,
+
+    /// Log whole digests, rather than `DigestExt::short` ones.
+    pub full_digests: bool,
+
+    /// Print how long each step took to stderr.
+    pub timing: bool,
+
+    /// Report what the pipeline would do, without running any of it.
+    pub dry_run: bool,
+
+    /// Keep running, and rebuild whenever an imported path changes.
+    pub watch: bool,
+
+    /// Worker threads for copying files in and out of the DB. Defaults to
+    /// one per core, see `threads()`.
+    pub concurrency: Option<NonZeroUsize>,
+
+    /// Refuse to export anywhere outside this directory, see `export::jailed`.
+    pub jail: Option<PathBuf>,
+
+    /// Also write a JSON manifest of the files each export wrote, see
+    /// `export::write_manifest`.
+    pub manifest: Option<PathBuf>,
+
+    /// Send the op log here instead of the terminal.
+    pub log_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Pull global flags out of a CLI arg list, leaving the pipeline behind.
+    ///
+    /// These can appear anywhere in the args, since they don't belong to any
+    /// particular op. Except as an op's required args, which are passed
+    /// through untouched, see `OpCode::required_args`.
+    pub fn from_args<T>(
+        args: impl IntoIterator<Item = T>,
+    ) -> Result<(Self, Vec<String>), ParseError>
+    where
+        T: AsRef<str>,
+    {
+        let mut config = Self::default();
+        let mut rest = vec![];
+        let mut it = args.into_iter().map(|a| a.as_ref().to_owned());
+        while let Some(arg) = it.next() {
+            if let Some(oc) = OpCode::from_arg(&arg) {
+                rest.push(arg);
+                rest.extend(it.by_ref().take(oc.required_args()));
+                continue;
+            }
+            match arg.as_ref() {
+                "--no-cache" => config.no_cache = true,
+                "--reproducible" => config.reproducible = true,
+                "--mime" => config.mime = true,
+                "--full-metadata" => config.scan.full_metadata = true,
+                "--skip-hidden" => config.scan.skip_hidden = true,
+                "--include-root" => config.scan.include_root = true,
+                "--error-on-special" => config.scan.special = SpecialFiles::Error,
+                "--keep-going" => config.scan.keep_going = true,
+                "--json" => config.json = true,
+                "--full-digests" => config.full_digests = true,
+                "--timing" => config.timing = true,
+                "--dry-run" => config.dry_run = true,
+                "--watch" => config.watch = true,
+                "--cache-limit" => {
+                    let bytes = it.next().ok_or(ParseError::MissingFlagArg {
+                        flag: "--cache-limit",
+                        name: "bytes",
+                    })?;
+                    let parsed = bytes
+                        .parse()
+                        .map_err(|e| ParseError::InvalidNumber(bytes, e))?;
+                    config.cache_limit = Some(parsed);
+                }
+                "--max-download" => {
+                    let bytes = it.next().ok_or(ParseError::MissingFlagArg {
+                        flag: "--max-download",
+                        name: "bytes",
+                    })?;
+                    let parsed = bytes
+                        .parse()
+                        .map_err(|e| ParseError::InvalidNumber(bytes, e))?;
+                    config.max_download = Some(parsed);
+                }
+                "--max-depth" => {
+                    let n = it.next().ok_or(ParseError::MissingFlagArg {
+                        flag: "--max-depth",
+                        name: "N",
+                    })?;
+                    let parsed = n.parse().map_err(|e| ParseError::InvalidNumber(n, e))?;
+                    config.scan.max_depth = Some(parsed);
+                }
+                "--concurrency" => {
+                    let n = it.next().ok_or(ParseError::MissingFlagArg {
+                        flag: "--concurrency",
+                        name: "N",
+                    })?;
+                    let parsed = n.parse().map_err(|e| ParseError::InvalidNumber(n, e))?;
+                    config.concurrency = Some(parsed);
+                }
+                "--jail" => {
+                    let dir = it.next().ok_or(ParseError::MissingFlagArg {
+                        flag: "--jail",
+                        name: "dir",
+                    })?;
+                    config.jail = Some(dir.into());
+                }
+                "--manifest" => {
+                    let path = it.next().ok_or(ParseError::MissingFlagArg {
+                        flag: "--manifest",
+                        name: "path",
+                    })?;
+                    config.manifest = Some(path.into());
+                }
+                "--log-file" => {
+                    let path = it.next().ok_or(ParseError::MissingFlagArg {
+                        flag: "--log-file",
+                        name: "path",
+                    })?;
+                    config.log_file = Some(path.into());
+                }
+                _ => rest.push(arg),
+            }
+        }
+        Ok((config, rest))
+    }
+
+    /// How many files to copy at once.
+    pub fn threads(&self) -> usize {
+        self.concurrency
+            .map_or_else(crate::pool::default_threads, NonZeroUsize::get)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_args() {
+        assert_eq!(
+            Config::from_args(["--empty", "--no-cache", "--merge"]),
+            Ok((
+                Config {
+                    no_cache: true,
+                    ..Config::default()
+                },
+                vec!["--empty".into(), "--merge".into()]
+            ))
+        );
+        assert_eq!(
+            Config::from_args(["--cache-limit", "1024", "--empty"]),
+            Ok((
+                Config {
+                    cache_limit: Some(1024),
+                    ..Config::default()
+                },
+                vec!["--empty".into()]
+            ))
+        );
+        assert_eq!(
+            Config::from_args(["--cache-limit"]),
+            Err(ParseError::MissingFlagArg {
+                flag: "--cache-limit",
+                name: "bytes",
+            })
+        );
+        assert!(Config::from_args(["--cache-limit", "lots"]).is_err());
+        let (config, _) = Config::from_args(["--max-depth", "1"]).unwrap();
+        assert_eq!(config.scan.max_depth, Some(1));
+        assert_eq!(
+            Config::from_args(["--max-download", "4096", "--empty"]),
+            Ok((
+                Config {
+                    max_download: Some(4096),
+                    ..Config::default()
+                },
+                vec!["--empty".into()]
+            ))
+        );
+        let (config, _) = Config::from_args(["--concurrency", "1"]).unwrap();
+        assert_eq!(config.threads(), 1);
+        assert!(Config::from_args(["--concurrency", "0"]).is_err());
+        assert!(Config::default().threads() >= 1);
+        assert_eq!(
+            Config::from_args(["--log-file", "ci.log", "--empty"]),
+            Ok((
+                Config {
+                    log_file: Some("ci.log".into()),
+                    ..Config::default()
+                },
+                vec!["--empty".into()]
+            ))
+        );
+        assert_eq!(
+            Config::from_args(["--export", "out", "--manifest", "out.json"]),
+            Ok((
+                Config {
+                    manifest: Some("out.json".into()),
+                    ..Config::default()
+                },
+                vec!["--export".into(), "out".into()]
+            ))
+        );
+        assert_eq!(
+            Config::from_args(["--jail", "build/out", "--empty"]),
+            Ok((
+                Config {
+                    jail: Some("build/out".into()),
+                    ..Config::default()
+                },
+                vec!["--empty".into()]
+            ))
+        );
+        assert_eq!(
+            Config::from_args([
+                "--reproducible",
+                "--mime",
+                "--full-metadata",
+                "--skip-hidden",
+                "--include-root",
+                "--error-on-special",
+                "--keep-going"
+            ]),
+            Ok((
+                Config {
+                    reproducible: true,
+                    mime: true,
+                    scan: ScanOptions {
+                        full_metadata: true,
+                        skip_hidden: true,
+                        include_root: true,
+                        max_depth: None,
+                        special: SpecialFiles::Error,
+                        prefix: String::new(),
+                        keep_going: true,
+                    },
+                    ..Config::default()
+                },
+                vec![]
+            ))
+        );
+        assert_eq!(
+            Config::from_args([
+                "--empty",
+                "--json",
+                "--timing",
+                "--full-digests",
+                "--dry-run",
+                "--watch"
+            ]),
+            Ok((
+                Config {
+                    json: true,
+                    dry_run: true,
+                    watch: true,
+                    timing: true,
+                    full_digests: true,
+                    ..Config::default()
+                },
+                vec!["--empty".into()]
+            ))
+        );
+    }
+
+    #[test]
+    fn op_args_look_like_flags() {
+        let args = [
+            "--empty",
+            "--file",
+            "notes.txt",
+            "--json",
+            "--cmd-impure",
+            "--dry-run",
+            "--no-cache",
+        ];
+        assert_eq!(
+            Config::from_args(args),
+            Ok((
+                Config {
+                    no_cache: true,
+                    ..Config::default()
+                },
+                args[..6].iter().map(|a| a.to_string()).collect()
+            ))
+        );
+        // Only the required ones, an import's targets could be anything.
+        let (config, rest) = Config::from_args(["--import", "--json", "a", "--json"]).unwrap();
+        assert!(config.json);
+        assert_eq!(rest, vec!["--import", "--json", "a"]);
+    }
+}
@@ -0,0 +1,67 @@
+use arkive::Contents;
+
+/// Extra behavior for `Contents`, which lives upstream in arkive.
+pub trait ContentsExt<C>: Sized {
+    /// Convert a file's contents, leaving a dir as a dir.
+    fn map<T>(self, f: impl FnOnce(C) -> T) -> Contents<T>;
+
+    /// Borrow a file's contents, without taking them.
+    fn as_ref(&self) -> Contents<&C>;
+
+    /// A file's contents, or `None` for a dir.
+    fn file(self) -> Option<C>;
+
+    /// The other way around from `file`: `None` is a dir.
+    fn from_option(c: Option<C>) -> Self;
+}
+
+impl<C> ContentsExt<C> for Contents<C> {
+    fn map<T>(self, f: impl FnOnce(C) -> T) -> Contents<T> {
+        match self {
+            Contents::Dir => Contents::Dir,
+            Contents::File(c) => Contents::File(f(c)),
+        }
+    }
+
+    fn as_ref(&self) -> Contents<&C> {
+        match self {
+            Contents::Dir => Contents::Dir,
+            Contents::File(c) => Contents::File(c),
+        }
+    }
+
+    fn file(self) -> Option<C> {
+        match self {
+            Contents::Dir => None,
+            Contents::File(c) => Some(c),
+        }
+    }
+
+    fn from_option(c: Option<C>) -> Self {
+        match c {
+            Some(c) => Contents::File(c),
+            None => Contents::Dir,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn map() {
+        assert_eq!(Contents::File("abc").map(str::len), Contents::File(3));
+        assert_eq!(Contents::<&str>::Dir.map(str::len), Contents::Dir);
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = Contents::File(String::from("x"));
+        assert_eq!(file.as_ref(), Contents::File(&"x".to_owned()));
+        assert_eq!(file.file(), Some("x".to_owned()));
+        assert_eq!(Contents::<u8>::Dir.file(), None);
+        assert_eq!(Contents::from_option(Some(1)), Contents::File(1));
+        assert_eq!(Contents::<u8>::from_option(None), Contents::Dir);
+    }
+}
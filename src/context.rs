@@ -1,14 +1,21 @@
+use crate::ark::StoredArk;
 use crate::behavior::exec_step;
+use crate::config::Config;
+use crate::digest::DigestExt;
+use crate::label::Label;
 use crate::logger::Logger;
-use crate::op::Op;
+use crate::op::{Op, OpCode};
 use arkive::*;
 use serde::Serialize;
 use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
 
 pub struct Context<'a> {
     pub db: &'a DB,
     pub log: &'a mut Logger,
     pub stack: Vec<Digest>,
+    pub config: Config,
 }
 
 impl<'a> Context<'a> {
@@ -17,24 +24,129 @@ impl<'a> Context<'a> {
             db: db,
             log: log,
             stack: vec![],
+            config: Config::default(),
         }
     }
 
+    /// Pick up where an earlier build left off, with the archive a `--tag`
+    /// label points at as the only thing on the stack.
+    pub fn from_label(db: &'a DB, log: &'a mut Logger, name: impl AsRef<str>) -> io::Result<Self> {
+        let label = Label::new(name)?;
+        let digest = label.read(db)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No label {:?} in this DB", label.name()),
+            )
+        })?;
+        let mut ctx = Self::new(db, log);
+        ctx.push(digest);
+        Ok(ctx)
+    }
+
     pub fn apply(&mut self, op: &Op) -> io::Result<()> {
-        ReadyStep::from(op, &mut self.stack)?.apply(self)
+        let mut step = ReadyStep::from(op, &mut self.stack)?;
+        step.narrow(self.db)?;
+        step.apply(self)
     }
 
     pub fn parse_apply(&mut self, args: Vec<String>) -> io::Result<()> {
         let pipeline = crate::op::parse_pipeline(args)?;
+        let pipeline = crate::op::fuse(crate::glob::expand_imports(pipeline)?);
+        check_arity(self.stack.len(), &pipeline)?;
+        if self.config.dry_run {
+            return self.dry_run(&pipeline);
+        }
         for op in pipeline {
             self.apply(&op)?
         }
         Ok(())
     }
 
+    /// Report what each step would do, without doing any of it.
+    ///
+    /// Cache lookups need the input digests, so we can only check them while
+    /// every earlier step came out of the cache too. After the first step that
+    /// would really run, later outputs are unknown.
+    pub fn dry_run(&mut self, pipeline: &[Op]) -> io::Result<()> {
+        check_arity(self.stack.len(), pipeline)?;
+
+        // None stands for an archive we'd only know by running something.
+        let mut stack: Vec<Option<Digest>> = self.stack.iter().copied().map(Some).collect();
+        for (n, op) in pipeline.iter().enumerate() {
+            let (consumes, produces) = op.stats(stack.len());
+            let consumed: Option<Vec<Digest>> = stack
+                .split_off(stack.len() - consumes)
+                .into_iter()
+                .collect();
+            let known = consumed.is_some();
+            let cached = match consumed {
+                Some(consumed) if !self.config.no_cache => {
+                    let mut step = ReadyStep(op.clone(), consumed, produces);
+                    // Stores the narrowed input, but that's all it writes.
+                    step.narrow(self.db)?;
+                    step.peek_cache(self.db)
+                }
+                _ => None,
+            };
+            let status = match (&cached, known) {
+                (Some(_), _) => "cached",
+                (None, true) => "would run",
+                (None, false) => "would run, after inputs are built",
+            };
+            writeln!(self.log.stdout, "{}. {:?}: {}", n + 1, op.to_code(), status)?;
+            match cached {
+                Some(digests) => stack.extend(digests.into_iter().map(Some)),
+                None => stack.extend(std::iter::repeat_n(None, produces)),
+            }
+        }
+        Ok(())
+    }
+
     pub fn push(&mut self, digest: Digest) {
         self.stack.push(digest)
     }
+
+    /// Run anything `Transform`, including a closure for a one-off step.
+    pub fn transform(&mut self, t: impl Transform) -> io::Result<&mut Self> {
+        t.transform(self)?;
+        Ok(self)
+    }
+}
+
+/// One or more steps to run against a Context.
+///
+/// Closures count, so embedders can slot custom archive work into a pipeline
+/// without adding to the `Op` enum. Those steps don't get op headers, timing
+/// or caching, since there's no `Op` to describe them.
+pub trait Transform {
+    fn transform(&self, ctx: &mut Context) -> io::Result<()>;
+}
+
+impl Transform for Op {
+    fn transform(&self, ctx: &mut Context) -> io::Result<()> {
+        ctx.apply(self)
+    }
+}
+
+impl<const N: usize> Transform for [Op; N] {
+    fn transform(&self, ctx: &mut Context) -> io::Result<()> {
+        self.iter().try_for_each(|op| ctx.apply(op))
+    }
+}
+
+impl Transform for Vec<Op> {
+    fn transform(&self, ctx: &mut Context) -> io::Result<()> {
+        self.iter().try_for_each(|op| ctx.apply(op))
+    }
+}
+
+impl<F> Transform for F
+where
+    F: Fn(&mut Context) -> io::Result<()>,
+{
+    fn transform(&self, ctx: &mut Context) -> io::Result<()> {
+        self(ctx)
+    }
 }
 
 impl Op {
@@ -42,28 +154,97 @@ impl Op {
     pub fn stats(&self, stack_size: usize) -> (usize, usize) {
         match self {
             Op::Empty => (0, 1),
+            // One per target, globs having been expanded at parse time.
             Op::Import { targets, .. } => (0, targets.len()),
             Op::Export(_) => (1, 0),
-            Op::Merge => (stack_size, 1),
+            Op::Merge(None, _) => (stack_size, 1),
+            Op::Merge(Some(n), _) => (*n, 1),
             Op::Prefix(_) => (1, 1),
             Op::Filter(_) => (1, 1),
             Op::Rename(_, _) => (1, 1),
+            Op::RenameAll(_, _) => (1, 1),
             Op::Download(_, _) => (0, 1),
             Op::DownloadImpure(_) => (0, 1),
-            Op::CmdImpure(_) => (1, 1),
+            Op::CmdImpure { .. } => (1, 1),
+            Op::CmdCached { .. } => (1, 1),
+            Op::Flatten => (1, 1),
+            Op::ExportMerge(_) => (1, 0),
+            Op::File(_, _) => (1, 1),
+            Op::Verify => (1, 1),
+            Op::Tag(_) => (1, 1),
+            Op::Labels => (0, 0),
+            Op::Extract(_) => (1, 1),
+            Op::PrefixMap(_) => (1, 1),
+            Op::Print => (0, 0),
+            Op::Assert(_) => (1, 1),
+            Op::Sign(_) => (1, 1),
+            Op::VerifySig(_) => (1, 1),
+            Op::Dedup => (1, 1),
+            Op::List => (1, 1),
+            Op::Fused(_) => (1, 1),
         }
     }
 }
 
+/// A step that would pop more archives than the stack will have by then.
+#[derive(Debug, PartialEq)]
+pub struct ArityError {
+    /// Counting from 1, like a person would.
+    pub step: usize,
+    pub op: OpCode,
+    pub consumes: usize,
+    pub available: usize,
+}
+impl From<ArityError> for std::io::Error {
+    fn from(e: ArityError) -> Self {
+        Self::other(format!(
+            "Step {} ({:?}) would consume {} archives, but the stack would only have {}",
+            e.step, e.op, e.consumes, e.available
+        ))
+    }
+}
+
+/// Check a whole pipeline's stack use before running any of it.
+///
+/// Otherwise a pipeline that underflows at step 5 still does the work (and
+/// the downloads) for steps 1-4 first.
+pub fn validate_pipeline(ops: &[Op]) -> Result<(), ArityError> {
+    check_arity(0, ops)
+}
+
+fn check_arity(mut depth: usize, ops: &[Op]) -> Result<(), ArityError> {
+    for (n, op) in ops.iter().enumerate() {
+        let (consumes, produces) = op.stats(depth);
+        if depth < consumes {
+            return Err(ArityError {
+                step: n + 1,
+                op: op.to_code(),
+                consumes,
+                available: depth,
+            });
+        }
+        depth = depth - consumes + produces;
+    }
+    Ok(())
+}
+
 /// Just the digests that are applicable to this operation.
 #[derive(Serialize)]
 pub struct ReadyStep(Op, Vec<Digest>, usize);
 
-// TODO: Rendering and conversion
-pub struct StackEmpty;
+/// A step run on its own, with fewer archives on the stack than it pops.
+#[derive(Debug, PartialEq)]
+pub struct StackEmpty {
+    pub op: OpCode,
+    pub consumes: usize,
+    pub available: usize,
+}
 impl From<StackEmpty> for std::io::Error {
-    fn from(_: StackEmpty) -> Self {
-        Self::other("Tried to pop entries off an empty stack")
+    fn from(e: StackEmpty) -> Self {
+        Self::other(format!(
+            "{:?} needs {} archives off the stack, but it only has {}",
+            e.op, e.consumes, e.available
+        ))
     }
 }
 
@@ -71,21 +252,50 @@ impl ReadyStep {
     pub fn from(op: &Op, stack: &mut Vec<Digest>) -> Result<Self, StackEmpty> {
         let (consumes, produces) = op.stats(stack.len());
         if stack.len() < consumes {
-            Err(StackEmpty)
+            Err(StackEmpty {
+                op: op.to_code(),
+                consumes,
+                available: stack.len(),
+            })
         } else {
             let pos = stack.len() - consumes;
             Ok(Self(op.clone(), stack.split_off(pos), produces))
         }
     }
 
+    /// Cut a command's input down to its declared `--inputs`.
+    ///
+    /// The command only ever sees that much, and it's what goes into the
+    /// cache key, so changes anywhere else don't cause a rebuild.
+    pub fn narrow(&mut self, db: &DB) -> io::Result<()> {
+        let (Op::CmdImpure { inputs, .. } | Op::CmdCached { inputs, .. }) = &self.0 else {
+            return Ok(());
+        };
+        if inputs.is_empty() {
+            return Ok(());
+        }
+        for digest in self.1.iter_mut() {
+            let ark: Ark<Digest> = Ark::load_checked(db, digest)?;
+            *digest = crate::glob::keep_matching(ark, inputs)?.store(db)?;
+        }
+        Ok(())
+    }
+
     pub fn cache_digests(&self, ctx: &mut Context) -> Option<Vec<Digest>> {
-        let key = self.cache_key();
-        let path = ctx.db.join("cache").join(key.to_hex());
+        let d = self.peek_cache(ctx.db)?;
+
+        // Best effort, this only feeds into eviction order.
+        let _ = touch(&ctx.db.join("cache").join(self.cache_key().to_hex()));
+        Some(d)
+    }
+
+    /// Like `cache_digests`, but leaves the entry's age alone.
+    fn peek_cache(&self, db: &DB) -> Option<Vec<Digest>> {
+        let path = db.join("cache").join(self.cache_key().to_hex());
         if path.exists() {
-            let read = std::fs::read(path).expect("failed to read cache entry");
+            let read = std::fs::read(&path).expect("failed to read cache entry");
             let s = String::from_utf8(read).expect("failed to interpret utf-8");
-            let d: Vec<Digest> = serde_json::from_str(&s).expect("failed to parse json");
-            Some(d)
+            Some(serde_json::from_str(&s).expect("failed to parse json"))
         } else {
             None
         }
@@ -93,14 +303,26 @@ impl ReadyStep {
     pub fn can_cache(&self) -> bool {
         match self.0 {
             Op::Empty => true,
-            Op::Merge => true,
+            Op::Merge(_, _) => true,
             Op::Prefix(_) => true,
             Op::Rename(_, _) => true,
+            Op::RenameAll(_, _) => true,
             Op::Filter(_) => true,
             Op::Download(_, _) => true,
+            Op::CmdCached { .. } => true,
+            Op::Flatten => true,
+            Op::File(_, _) => true,
+            Op::PrefixMap(_) => true,
+            // Only ever holds path-only ops, which can all be cached.
+            Op::Fused(_) => true,
             _ => false,
         }
     }
+    /// Covers the op and its arguments, plus the digests it consumes.
+    ///
+    /// That second part is what makes `CmdCached` safe-ish to cache: the same
+    /// command on the same input archive gets the same key. We still have to
+    /// trust the command not to read anything from outside that archive.
     pub fn cache_key(&self) -> Digest {
         serde_json::to_string(self)
             .expect("Failed to serialize Op")
@@ -108,42 +330,385 @@ impl ReadyStep {
     }
 
     pub fn apply(&self, ctx: &mut Context) -> io::Result<()> {
-        let sep = "================================================================";
-        write!(
-            ctx.log.opheader(),
-            "{}\n{:?}\n{}\n",
-            sep,
-            self.0.to_code(),
-            sep
-        )?;
+        let json = ctx.config.json;
+        if !json {
+            let sep = "================================================================";
+            write!(
+                ctx.log.opheader(),
+                "{}\n{:?}\n{}\n",
+                sep,
+                self.0.to_code(),
+                sep
+            )?;
+        }
 
-        let cache_digests = self.cache_digests(ctx);
+        let start = Instant::now();
+        let use_cache = !ctx.config.no_cache;
+        let cache_digests = if use_cache {
+            self.cache_digests(ctx)
+        } else {
+            None
+        };
         let has_cache = cache_digests.is_some();
-        let can_cache = self.can_cache();
-        write!(
-            ctx.log.opheader(),
-            " + Can cache? {}\n + Is in cache? {}\n",
-            can_cache,
-            has_cache,
-        )?;
+        let can_cache = use_cache && self.can_cache();
+        if !json {
+            write!(
+                ctx.log.opheader(),
+                " + Can cache? {}\n + Is in cache? {}\n",
+                can_cache,
+                has_cache,
+            )?;
+        }
 
         if has_cache {
             ctx.stack.extend(cache_digests.unwrap());
         } else {
             exec_step(ctx, &self.0, &self.1)?;
         }
+        let elapsed = start.elapsed();
+        writeln!(ctx.log.timing(), "{:?}: {:?}", self.0.to_code(), elapsed)?;
+
+        let n_produced = self.2;
+        let pos = &ctx.stack.len() - n_produced;
+        let event = StepEvent {
+            op: self.0.to_code(),
+            consumed: self.1.clone(),
+            produced: ctx.stack[pos..].to_vec(),
+            cached: has_cache,
+            millis: elapsed.as_millis(),
+        };
 
         if can_cache {
-            let n_produced = self.2;
-            let pos = &ctx.stack.len() - n_produced;
-            let produced_digests = &ctx.stack[pos..];
-            let cache_path = ctx.db.join("cache").join(self.cache_key().to_hex());
-            std::fs::write(cache_path, serde_json::to_string(produced_digests)?)?;
+            let cache_dir = ctx.db.join("cache");
+            let cache_path = cache_dir.join(self.cache_key().to_hex());
+
+            // DBs from older versions (or with a hand-cleared cache) may not
+            // have this dir yet.
+            std::fs::create_dir_all(&cache_dir)?;
+            std::fs::write(cache_path, serde_json::to_string(&event.produced)?)?;
+
+            if let Some(limit) = ctx.config.cache_limit {
+                prune_cache(&cache_dir, limit)?;
+            }
+        }
+
+        if json {
+            writeln!(ctx.log.stdout, "{}", serde_json::to_string(&event)?)?;
+        } else {
+            for digest in &ctx.stack {
+                let hex = if ctx.config.full_digests {
+                    digest.to_hex()
+                } else {
+                    digest.short()
+                };
+                write!(ctx.log.stack(), "{}\n", hex)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What happened in a single step.
+///
+/// This is the data behind both log formats. `--json` prints it as-is.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StepEvent {
+    pub op: OpCode,
+    pub consumed: Vec<Digest>,
+    pub produced: Vec<Digest>,
+    pub cached: bool,
+    pub millis: u128,
+}
+
+/// Bump the mtime of a cache entry, so it looks recently used.
+fn touch(path: &Path) -> io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Delete the least recently used cache entries until we fit in the limit.
+pub fn prune_cache(dir: &Path, limit: u64) -> io::Result<()> {
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        entries.push((meta.modified()?, meta.len(), entry.path()));
+    }
+
+    // Oldest first
+    entries.sort();
+    let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in entries {
+        if total <= limit {
+            break;
         }
+        std::fs::remove_file(path)?;
+        total -= len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ark::{ArkExt, MergeStrategy, StoredArk};
+    use crate::logger::Policy;
 
-        for digest in &ctx.stack {
-            write!(ctx.log.stack(), "{}\n", digest.to_hex())?;
+    fn cache_entries(db: &DB) -> usize {
+        std::fs::read_dir(db.join("cache"))
+            .expect("Read cache dir")
+            .count()
+    }
+
+    #[test]
+    fn no_cache() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.config.no_cache = true;
+        ctx.empty()?.empty()?;
+        assert_eq!(cache_entries(&db), 0);
+        assert!(!log.recorded().0.contains("Is in cache? true"));
+        Ok(())
+    }
+
+    #[test]
+    fn from_label() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let base = {
+            let db = DB::new(dir.path())?;
+            let mut log = Logger::new_vec();
+            let mut ctx = Context::new(&db, &mut log);
+            ctx.import(".", ["fixture"])?.tag("@base")?;
+            ctx.stack[0]
+        };
+
+        // Same DB on disk, but nothing else carried over.
+        let db = DB::new(dir.path())?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::from_label(&db, &mut log, "@base")?;
+        assert_eq!(ctx.stack, vec![base]);
+        ctx.prefix("layer")?;
+        let ark: Ark<Digest> = Ark::load(&db, &ctx.stack[0])?;
+        assert!(ark.get("layer/fixture/file_at_root.txt").is_some());
+
+        let mut log = Logger::new_vec();
+        let Err(err) = Context::from_label(&db, &mut log, "@nope") else {
+            panic!("No such label")
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn cache_limit() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.empty()?.prefix("a")?.prefix("b")?;
+        assert_eq!(cache_entries(&db), 3);
+
+        ctx.config.cache_limit = Some(0);
+        ctx.prefix("c")?;
+        assert_eq!(cache_entries(&db), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_cache_dir() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        std::fs::remove_dir(db.join("cache"))?;
+
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.empty()?;
+        assert_eq!(cache_entries(&db), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn json() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.config.json = true;
+        let empty = ctx.empty()?.stack[0];
+        let prefixed = ctx.prefix("a")?.stack[0];
+
+        let lines: Vec<serde_json::Value> = log
+            .recorded()
+            .0
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("Every line is JSON"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["op"], "Empty");
+        assert_eq!(lines[0]["consumed"], serde_json::json!([]));
+        assert_eq!(lines[0]["produced"], serde_json::json!([empty.to_hex()]));
+        assert_eq!(lines[1]["op"], "Prefix");
+        assert_eq!(lines[1]["consumed"], serde_json::json!([empty.to_hex()]));
+        assert_eq!(lines[1]["produced"], serde_json::json!([prefixed.to_hex()]));
+        Ok(())
+    }
+
+    #[test]
+    fn timing() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        Context::new(&db, &mut log).empty()?;
+        assert!(!log.recorded().1.contains("Empty: "));
+
+        log.pol.timing = Policy::Stderr;
+        Context::new(&db, &mut log).empty()?;
+        let timing = log.recorded().1;
+        assert!(timing.starts_with("Empty: "), "{}", timing);
+        assert!(timing.ends_with("s\n"), "{}", timing);
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        Context::new(&db, &mut log).empty()?.prefix("foo")?;
+
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.config.dry_run = true;
+        ctx.parse_apply(
+            [
+                "--empty", "--prefix", "foo", "--import", ".", "fixture", "--merge",
+            ]
+            .map(String::from)
+            .to_vec(),
+        )?;
+        assert_eq!(ctx.stack, vec![]);
+        assert_eq!(
+            log.recorded().0,
+            "1. Empty: cached\n\
+             2. Prefix: cached\n\
+             3. Import: would run\n\
+             4. Merge: would run, after inputs are built\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_arity() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("out").to_str().unwrap().to_owned();
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.config.dry_run = true;
+        let args = [
+            "--import", ".", "fixture", "--export", &out, "--export", &out,
+        ];
+        let err = ctx
+            .parse_apply(args.map(String::from).to_vec())
+            .expect_err("Second export has nothing to consume");
+        assert_eq!(
+            err.to_string(),
+            "Step 3 (Export) would consume 1 archives, but the stack would only have 0"
+        );
+
+        // Nothing was imported or exported
+        assert_eq!(std::fs::read_dir(db.join("cas"))?.count(), 0);
+        assert!(!Path::new(&out).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        let empty = || Op::Empty;
+        let export = || Op::Export("out".into());
+        assert_eq!(validate_pipeline(&[]), Ok(()));
+        assert_eq!(
+            validate_pipeline(&[empty(), Op::Merge(None, MergeStrategy::LastWins), export()]),
+            Ok(())
+        );
+        assert_eq!(
+            validate_pipeline(&[Op::Merge(None, MergeStrategy::LastWins)]),
+            Ok(())
+        ); // Merges nothing
+        assert_eq!(
+            validate_pipeline(&[empty(), Op::Merge(Some(2), MergeStrategy::LastWins)]),
+            Err(ArityError {
+                step: 2,
+                op: OpCode::Merge,
+                consumes: 2,
+                available: 1,
+            })
+        );
+        assert_eq!(
+            validate_pipeline(&[empty(), export(), Op::Flatten, export()]),
+            Err(ArityError {
+                step: 3,
+                op: OpCode::Flatten,
+                consumes: 1,
+                available: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn arity_checked_up_front() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        let args = [
+            "--import",
+            ".",
+            "fixture",
+            "--flatten",
+            "--export",
+            "out",
+            "--flatten",
+        ];
+        let err = ctx
+            .parse_apply(args.map(String::from).to_vec())
+            .expect_err("Last flatten has nothing to consume");
+        assert!(err.to_string().starts_with("Step 4 (Flatten)"), "{}", err);
+        assert_eq!(ctx.stack, vec![]);
+        assert_eq!(std::fs::read_dir(db.join("cas"))?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn transforms() -> io::Result<()> {
+        let db = DB::new_temp()?;
+        let mut log = Logger::new_vec();
+        let mut ctx = Context::new(&db, &mut log);
+        ctx.transform(Op::Empty)?
+            .transform([Op::Empty, Op::Merge(None, MergeStrategy::LastWins)])?
+            .transform(vec![Op::Prefix("a".into())])?;
+        assert_eq!(ctx.stack.len(), 1);
+
+        ctx.transform(|ctx: &mut Context| {
+            let digest = Ark::<Digest>::empty().store(ctx.db)?;
+            ctx.push(digest);
+            Ok(())
+        })?;
+        assert_eq!(ctx.stack.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn prune_oldest_first() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["old", "new"] {
+            std::fs::write(dir.path().join(name), "1234")?;
         }
+        let old = std::fs::File::options()
+            .write(true)
+            .open(dir.path().join("old"))?;
+        old.set_modified(SystemTime::UNIX_EPOCH)?;
+
+        prune_cache(dir.path(), 4)?;
+        assert!(!dir.path().join("old").exists());
+        assert!(dir.path().join("new").exists());
         Ok(())
     }
 }
@@ -0,0 +1,183 @@
+use crate::ark::StoredArk;
+use crate::digest::DigestExt;
+use crate::error::DirtabaseError;
+use arkive::{Ark, Digest, DB};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Extra behavior for the `DB` type, which lives upstream in arkive.
+pub trait DBExt {
+    /// Create a temporary directory within the DB. Deleted on drop.
+    ///
+    /// Staying inside the DB keeps us on the same filesystem as the CAS, so
+    /// files can be renamed into place instead of copied. This also creates
+    /// `tmp/` if it doesn't exist yet, rather than trusting the DB to have it.
+    fn tempdir(&self) -> Result<TempDir>;
+
+    /// Where a blob with this digest lives (or would live) in the CAS.
+    fn cas_path(&self, digest: &Digest) -> PathBuf;
+
+    /// Is a blob with this digest already stored?
+    fn exists(&self, digest: &Digest) -> bool {
+        self.cas_path(digest).exists()
+    }
+
+    /// Re-hash a stored blob, to check it still matches its name.
+    ///
+    /// Nothing else notices bit rot or a botched copy, since reads just hand
+    /// back whatever bytes are there. A missing blob is an error.
+    fn verify(&self, digest: &Digest) -> Result<bool> {
+        let blob = File::open(self.cas_path(digest))?;
+        Ok(Digest::from_reader(blob)? == *digest)
+    }
+}
+
+impl DBExt for DB {
+    fn tempdir(&self) -> Result<TempDir> {
+        let tmp = self.join("tmp");
+        std::fs::create_dir_all(&tmp)?;
+        tempfile::tempdir_in(tmp)
+    }
+
+    fn cas_path(&self, digest: &Digest) -> PathBuf {
+        self.join("cas").join(digest.to_hex())
+    }
+}
+
+/// On-disk layout version, recorded in the DB's `VERSION` file.
+///
+/// Bump this whenever something in the DB changes in a way older builds
+/// would misread, like the archive encoding or how the CAS is laid out.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Open (or create) a DB at `path`, checking it's a format we understand.
+///
+/// A DB without a `VERSION` file is either brand new, or from before there
+/// were versions, which is the same layout as v1. Either way it gets tagged
+/// with the current version. One from a newer dirtabase is refused up front,
+/// rather than failing in some confusing way halfway through a build.
+pub fn open(path: impl AsRef<Path>) -> Result<DB> {
+    let db = DB::new(path)?;
+    let version_path = db.join("VERSION");
+    let text = match std::fs::read_to_string(&version_path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            // Rename into place, so nobody reads a half-written version.
+            let tmp = db.tempdir()?;
+            let staged = tmp.path().join("VERSION");
+            std::fs::write(&staged, format!("{}\n", FORMAT_VERSION))?;
+            std::fs::rename(staged, version_path)?;
+            return Ok(db);
+        }
+        Err(e) => return Err(e),
+    };
+    let found: u32 = text.trim().parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("DB VERSION file doesn't hold a version: {:?}", text),
+        )
+    })?;
+    if found > FORMAT_VERSION {
+        return Err(DirtabaseError::UnsupportedFormat {
+            found,
+            supported: FORMAT_VERSION,
+        }
+        .into());
+    }
+    Ok(db)
+}
+
+/// Copy a stored archive and all of its files from one DB to another.
+///
+/// Blobs that the destination already has are skipped, which is most of them
+/// when pushing a new build of something that's been pushed before. Blobs are
+/// copied file to file, so this never holds a whole file in memory.
+pub fn copy_archive(src: &DB, dst: &DB, digest: &Digest) -> Result<Digest> {
+    let ark: Ark<Digest> = Ark::load_checked(src, digest)?;
+    let tmp = dst.tempdir()?;
+    for blob in ark.contents().iter().chain([digest]) {
+        if dst.exists(blob) {
+            continue;
+        }
+        // Copy to the side and rename, so a half-copied blob is never visible.
+        let staged = tmp.path().join(blob.to_hex());
+        std::fs::copy(src.cas_path(blob), &staged)?;
+        std::fs::rename(staged, dst.cas_path(blob))?;
+    }
+    Ok(*digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tempdir() -> Result<()> {
+        let db = DB::new_temp()?;
+        std::fs::remove_dir(db.join("tmp"))?;
+
+        let dir = db.tempdir()?;
+        assert!(dir.path().starts_with(db.join("tmp")));
+        assert!(dir.path().is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> Result<()> {
+        let db = DB::new_temp()?;
+        let digest = Ark::scan("fixture")?.import(&db)?;
+        assert!(db.verify(&digest)?);
+
+        std::fs::write(db.cas_path(&digest), "rotten")?;
+        assert!(!db.verify(&digest)?);
+        assert!(db.verify(&Digest::from("never stored")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn open_versioned() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db");
+        open(&path)?;
+        assert_eq!(std::fs::read_to_string(path.join("VERSION"))?, "1\n");
+        open(&path)?;
+
+        std::fs::write(path.join("VERSION"), "2")?;
+        let Err(err) = open(&path) else {
+            panic!("Too new")
+        };
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(
+            err.to_string(),
+            "Store format v2 not supported, this dirtabase only reads up to v1"
+        );
+
+        std::fs::write(path.join("VERSION"), "two")?;
+        let Err(err) = open(&path) else {
+            panic!("Garbage")
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn copy() -> Result<()> {
+        let src = DB::new_temp()?;
+        let dst = DB::new_temp()?;
+        let digest = Ark::scan("fixture")?.import(&src)?;
+        assert!(!dst.exists(&digest));
+
+        assert_eq!(copy_archive(&src, &dst, &digest)?, digest);
+        let ark: Ark<Digest> = Ark::load(&dst, &digest)?;
+        for blob in ark.contents() {
+            assert!(dst.exists(blob));
+        }
+
+        // Second time around is a no-op
+        assert_eq!(copy_archive(&src, &dst, &digest)?, digest);
+        assert_eq!(std::fs::read_dir(dst.join("cas"))?.count(), 3);
+        Ok(())
+    }
+}
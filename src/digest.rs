@@ -0,0 +1,106 @@
+use arkive::{Digest, D};
+use sha2::{Digest as _, Sha256};
+use std::cmp::Ordering;
+use std::io::{ErrorKind, Read, Result, Write};
+
+/// How many hex chars `short()` keeps. Same default as git.
+pub const SHORT_LEN: usize = 12;
+
+/// Extra behavior for digests, which live upstream in arkive.
+pub trait DigestExt {
+    /// Abbreviated hex, for logs.
+    ///
+    /// Only for display! Collisions at this length are unlikely but possible,
+    /// so storage and lookups always use the full digest.
+    fn short(&self) -> String;
+
+    /// Total order by bytes, which is the same as ordering by hex.
+    ///
+    /// `D` doesn't implement `Ord` upstream (and we can't add it from here),
+    /// so use this with `sort_by` when a listing needs a stable order.
+    fn cmp_bytes(&self, other: &Self) -> Ordering;
+
+    /// Hash everything `src` gives, a chunk at a time.
+    ///
+    /// Unlike `Digest::from`, nothing has to fit in memory, and any reader
+    /// works: empty files, pipes and devices all go through the same path.
+    fn from_reader(src: impl Read) -> Result<Self>
+    where
+        Self: Sized;
+}
+
+impl DigestExt for Digest {
+    fn short(&self) -> String {
+        let mut hex = self.to_hex();
+        hex.truncate(SHORT_LEN);
+        hex
+    }
+
+    fn cmp_bytes(&self, other: &Self) -> Ordering {
+        self.to_bytes().cmp(other.to_bytes())
+    }
+
+    fn from_reader(src: impl Read) -> Result<Self> {
+        hash_copy(src, std::io::sink())
+    }
+}
+
+/// Stream `src` into `out`, returning the digest of everything that went by.
+///
+/// Use `DigestExt::from_reader` to just hash.
+pub fn hash_copy(mut src: impl Read, mut out: impl Write) -> Result<Digest> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..n]);
+        out.write_all(&buf[..n])?;
+    }
+    Ok(D::from_bytes(&hasher.finalize().into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn short() {
+        let d = Digest::from("foo");
+        assert_eq!(d.short(), "2c26b46b68ff");
+        assert!(d.to_hex().starts_with(&d.short()));
+    }
+
+    #[test]
+    fn cmp_bytes() {
+        let mut digests: Vec<Digest> = ["a", "b", "c", "d"].map(Digest::from).into();
+        digests.sort_by(|a, b| a.cmp_bytes(b));
+        let hex: Vec<String> = digests.iter().map(|d| d.to_hex()).collect();
+        let mut sorted = hex.clone();
+        sorted.sort();
+        assert_eq!(hex, sorted);
+        assert_eq!(digests[0].cmp_bytes(&digests[0]), Ordering::Equal);
+    }
+
+    #[test]
+    fn from_reader() -> Result<()> {
+        assert_eq!(Digest::from_reader(std::io::empty())?, Digest::from(""));
+
+        // Several buffers' worth, with a short read at the end.
+        let big = vec![7u8; 200_000];
+        assert_eq!(Digest::from_reader(&big[..])?, Digest::from(&big));
+
+        let mut child = std::process::Command::new("printf")
+            .arg("piped")
+            .stdout(std::process::Stdio::piped())
+            .spawn()?;
+        let digest = Digest::from_reader(child.stdout.take().expect("piped"))?;
+        child.wait()?;
+        assert_eq!(digest, Digest::from("piped"));
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use crate::ark::{empty_digest, MergeStrategy, EMPTY_ARCHIVE_DIGEST};
 use crate::context::Context;
 use crate::op::{Op, OpCode};
 use crate::test_tools::*;
@@ -5,6 +6,11 @@ use arkive::Digest;
 use std::path::Path;
 use strum::IntoEnumIterator;
 
+/// Secret and public halves of the key the signing examples use. Obviously,
+/// don't use this one for anything real.
+const EXAMPLE_KEY: &str = "0707070707070707070707070707070707070707070707070707070707070707";
+const EXAMPLE_PUBKEY: &str = "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c";
+
 #[allow(dead_code)]
 pub struct OpDoc {
     flag: &'static str,
@@ -39,7 +45,7 @@ impl OpCode {
             OpCode::Import => OpDoc {
                 flag: "--import",
                 args: " base [target...]",
-                short: "Copy directories into the DB as archives.",
+                short: "Copy directories or files into the DB as archives. Globs like 'pkgs/*' give one archive per match.",
                 examples: vec![ExamplePipeline {
                     as_txt: vec!["--import", ".", "fixture"],
                     as_ops: vec![Op::Import {
@@ -74,9 +80,10 @@ impl OpCode {
             },
             OpCode::Merge => OpDoc {
                 flag: "--merge",
-                args: "",
-                short: "Merge all archives on the stack into one.",
-                examples: vec![ExamplePipeline {
+                args: " [N] [last-wins|first-wins|merge-attrs]",
+                short: "Merge the top N archives on the stack into one, or all of them. Where paths clash, the later archive wins by default; first-wins keeps the earlier entry, and merge-attrs keeps the later contents but combines both entries' attrs.",
+                examples: vec![
+                    ExamplePipeline {
                     as_txt: vec![
                         "--import", ".", "fixture", "src", "--merge", "--export", "./out",
                     ],
@@ -85,7 +92,7 @@ impl OpCode {
                             base: ".".into(),
                             targets: vec!["fixture".into(), "src".into()],
                         },
-                        Op::Merge,
+                        Op::Merge(None, MergeStrategy::LastWins),
                         Op::Export("./out".into()),
                     ],
                     as_ctx: &|ctx: &mut Context| {
@@ -96,7 +103,54 @@ impl OpCode {
                         assert!(Path::new("./out/src/doc.rs").exists());
                         Ok(())
                     },
-                }],
+                    },
+                    ExamplePipeline {
+                        as_txt: vec![
+                            "--empty", "--import", ".", "fixture", "src", "--merge", "2",
+                            "--export", "./out",
+                        ],
+                        as_ops: vec![
+                            Op::Empty,
+                            Op::Import {
+                                base: ".".into(),
+                                targets: vec!["fixture".into(), "src".into()],
+                            },
+                            Op::Merge(Some(2), MergeStrategy::LastWins),
+                            Op::Export("./out".into()),
+                        ],
+                        as_ctx: &|ctx: &mut Context| {
+                            ctx.empty()?
+                                .import(".", ["fixture", "src"])?
+                                .merge_top(2)?
+                                .export("./out")?;
+                            assert!(Path::new("./out/src/doc.rs").exists());
+                            assert_eq!(ctx.stack.len(), 1); // The empty one
+                            Ok(())
+                        },
+                    },
+                    ExamplePipeline {
+                        as_txt: vec![
+                            "--empty", "--file", "app.conf", "base", "--empty", "--file",
+                            "app.conf", "override", "--merge", "first-wins",
+                        ],
+                        as_ops: vec![
+                            Op::Empty,
+                            Op::File("app.conf".into(), "base".into()),
+                            Op::Empty,
+                            Op::File("app.conf".into(), "override".into()),
+                            Op::Merge(None, MergeStrategy::FirstWins),
+                        ],
+                        as_ctx: &|ctx: &mut Context| {
+                            ctx.empty()?
+                                .file("app.conf", "base")?
+                                .empty()?
+                                .file("app.conf", "override")?
+                                .merge_with(None, MergeStrategy::FirstWins)?;
+                            assert_eq!(ctx.stack.len(), 1);
+                            Ok(())
+                        },
+                    },
+                ],
             },
             OpCode::Prefix => OpDoc {
                 flag: "--prefix",
@@ -123,6 +177,41 @@ impl OpCode {
                     },
                 }],
             },
+            OpCode::PrefixMap => OpDoc {
+                flag: "--prefix-map",
+                args: " old=new[,old2=new2...]",
+                short: "Move paths from one prefix to another in the top archive. The longest matching old prefix wins.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--import",
+                        ".",
+                        "fixture",
+                        "--prefix-map",
+                        "fixture=data,fixture/dir1=nested",
+                        "--export",
+                        "./out",
+                    ],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::PrefixMap(vec![
+                            ("fixture".into(), "data".into()),
+                            ("fixture/dir1".into(), "nested".into()),
+                        ]),
+                        Op::Export("./out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?
+                            .prefix_map(&[("fixture", "data"), ("fixture/dir1", "nested")])?
+                            .export("./out")?;
+                        assert!(Path::new("./out/data/file_at_root.txt").exists());
+                        assert!(Path::new("./out/nested/dir2/nested.txt").exists());
+                        Ok(())
+                    },
+                }],
+            },
             OpCode::Filter => OpDoc {
                 flag: "--filter",
                 args: " pattern",
@@ -152,7 +241,7 @@ impl OpCode {
             OpCode::Rename => OpDoc {
                 flag: "--rename",
                 args: " pattern replacement",
-                short: "Rename files and directories within the top archive on the stack.",
+                short: "Rename files and directories within the top archive on the stack. Only the first match in each path is replaced, and the replacement can use groups like $1.",
                 examples: vec![ExamplePipeline {
                     as_txt: vec![
                         "--import", ".", "fixture", "--rename", "root", "boot", "--export", "./out",
@@ -174,6 +263,31 @@ impl OpCode {
                     },
                 }],
             },
+            OpCode::RenameAll => OpDoc {
+                flag: "--rename-all",
+                args: " pattern replacement",
+                short: "Like --rename, but replace every match in each path, not just the first.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--import", ".", "fixture", "--rename-all", "dir(\\d)", "d$1", "--export", "./out",
+                    ],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::RenameAll("dir(\\d)".into(), "d$1".into()),
+                        Op::Export("./out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?
+                            .rename_all("dir(\\d)", "d$1")?
+                            .export("./out")?;
+                        assert!(Path::new("./out/fixture/d1/d2/nested.txt").exists());
+                        Ok(())
+                    },
+                }],
+            },
             OpCode::Download => OpDoc {
                 flag: "--download",
                 args: " url digest",
@@ -226,7 +340,11 @@ impl OpCode {
                     as_txt: vec!["--empty", "--cmd-impure", "touch grass", "--export", "out"],
                     as_ops: vec![
                         Op::Empty,
-                        Op::CmdImpure("touch grass".into()),
+                        Op::CmdImpure {
+                            cmd: "touch grass".into(),
+                            inputs: vec![],
+                            outputs: vec![],
+                        },
                         Op::Export("out".into()),
                     ],
                     as_ctx: &|ctx: &mut Context| {
@@ -236,13 +354,449 @@ impl OpCode {
                     },
                 }],
             },
+            OpCode::CmdCached => OpDoc {
+                flag: "--cmd-cached",
+                args: " cmd",
+                short: "Like --cmd-impure, but cache results by command and input archive.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--empty", "--cmd-cached", "touch grass", "--export", "out"],
+                    as_ops: vec![
+                        Op::Empty,
+                        Op::CmdCached {
+                            cmd: "touch grass".into(),
+                            inputs: vec![],
+                            outputs: vec![],
+                        },
+                        Op::Export("out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?.cmd_cached("touch grass")?.export("out")?;
+                        assert!(Path::new("./out/grass").exists());
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Outputs => OpDoc {
+                flag: "--outputs",
+                args: " pattern [pattern...]",
+                short: "Declare what the command just before this makes. Only paths matching these globs (and the dirs above them) are kept from its result.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--empty",
+                        "--cmd-impure",
+                        "mkdir build && touch build/app scratch",
+                        "--outputs",
+                        "build/**",
+                        "--export",
+                        "out",
+                    ],
+                    as_ops: vec![
+                        Op::Empty,
+                        Op::CmdImpure {
+                            cmd: "mkdir build && touch build/app scratch".into(),
+                            inputs: vec![],
+                            outputs: vec!["build/**".into()],
+                        },
+                        Op::Export("out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?
+                            .cmd_impure_with(
+                                "mkdir build && touch build/app scratch",
+                                &[],
+                                &["build/**"],
+                            )?
+                            .export("out")?;
+                        assert!(Path::new("./out/build/app").exists());
+                        assert!(!Path::new("./out/scratch").exists());
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Inputs => OpDoc {
+                flag: "--inputs",
+                args: " pattern [pattern...]",
+                short: "Declare what the command just before this reads. It only sees paths matching these globs, so --cmd-cached doesn't rerun when anything else changes.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--import",
+                        ".",
+                        "fixture",
+                        "--cmd-cached",
+                        "ls -R > listing",
+                        "--inputs",
+                        "fixture/dir1/**",
+                        "--export",
+                        "out",
+                    ],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::CmdCached {
+                            cmd: "ls -R > listing".into(),
+                            inputs: vec!["fixture/dir1/**".into()],
+                            outputs: vec![],
+                        },
+                        Op::Export("out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?
+                            .cmd_cached_with("ls -R > listing", &["fixture/dir1/**"], &[])?
+                            .export("out")?;
+                        assert!(Path::new("./out/fixture/dir1/dir2/nested.txt").exists());
+                        assert!(!Path::new("./out/fixture/file_at_root.txt").exists());
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::ExportMerge => OpDoc {
+                flag: "--export-merge",
+                args: " dest",
+                short: "Write an archive into a directory, keeping files it doesn't replace.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--import", ".", "fixture", "--export-merge", "./src"],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::ExportMerge("./src".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?.export_merge("./src")?;
+                        assert!(Path::new("./src/fixture/dir1/dir2/nested.txt").exists());
+                        assert!(Path::new("./src/lib.rs").exists());
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Flatten => OpDoc {
+                flag: "--flatten",
+                args: "",
+                short: "Strip a single top-level directory wrapping the top archive.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--import", ".", "fixture", "--flatten", "--export", "./out"],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::Flatten,
+                        Op::Export("./out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?.flatten()?.export("./out")?;
+                        assert!(Path::new("./out/dir1/dir2/nested.txt").exists());
+                        assert!(Path::new("./out/file_at_root.txt").exists());
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::File => OpDoc {
+                flag: "--file",
+                args: " path content",
+                short: "Add a file with inline contents to the top archive, replacing any file already there.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--empty",
+                        "--file",
+                        "Dockerfile",
+                        "FROM scratch",
+                        "--export",
+                        "./out",
+                    ],
+                    as_ops: vec![
+                        Op::Empty,
+                        Op::File("Dockerfile".into(), "FROM scratch".into()),
+                        Op::Export("./out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?
+                            .file("Dockerfile", "FROM scratch")?
+                            .export("./out")?;
+                        assert_eq!(std::fs::read_to_string("./out/Dockerfile")?, "FROM scratch");
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Tag => OpDoc {
+                flag: "--tag",
+                args: " label",
+                short: "Point a label (like @release-1.0) at the top archive, leaving it on the stack.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--import", ".", "fixture", "--tag", "@fixture"],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::Tag("@fixture".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?.tag("@fixture")?;
+                        let label = crate::label::Label::new("@fixture")?;
+                        assert_eq!(label.read(ctx.db)?, Some(ctx.stack[0]));
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Print => OpDoc {
+                flag: "--print",
+                args: "",
+                short: "Print the whole stack to stdout, one hex digest per line. The op log moves to stderr, so this works in $(...).",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--import", ".", "fixture", "--print"],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::Print,
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?.print()?;
+                        let hex = ctx.stack[0].to_hex();
+                        assert!(ctx.log.recorded().0.contains(&hex));
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Assert => OpDoc {
+                flag: "--assert",
+                args: " hash",
+                short: "Fail unless the top archive has this digest. Leaves the stack as it was.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--empty", "--assert", EMPTY_ARCHIVE_DIGEST],
+                    as_ops: vec![Op::Empty, Op::Assert(empty_digest())],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?.assert(EMPTY_ARCHIVE_DIGEST)?;
+                        assert_eq!(ctx.stack, vec![empty_digest()]);
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Sign => OpDoc {
+                flag: "--sign",
+                args: " keyfile",
+                short: "Sign the top archive with the ed25519 secret key in keyfile, as 64 hex chars. The signature is stored beside the archive, so its digest doesn't change. Leaves the stack as it was.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--empty",
+                        "--file",
+                        "key.hex",
+                        EXAMPLE_KEY,
+                        "--export",
+                        "./out",
+                        "--empty",
+                        "--sign",
+                        "./out/key.hex",
+                        "--verify-sig",
+                        EXAMPLE_PUBKEY,
+                    ],
+                    as_ops: vec![
+                        Op::Empty,
+                        Op::File("key.hex".into(), EXAMPLE_KEY.into()),
+                        Op::Export("./out".into()),
+                        Op::Empty,
+                        Op::Sign("./out/key.hex".into()),
+                        Op::VerifySig(EXAMPLE_PUBKEY.into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?
+                            .file("key.hex", EXAMPLE_KEY)?
+                            .export("./out")?
+                            .empty()?
+                            .sign("./out/key.hex")?
+                            .verify_sig(EXAMPLE_PUBKEY)?;
+                        assert_eq!(ctx.stack, vec![empty_digest()]);
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::VerifySig => OpDoc {
+                flag: "--verify-sig",
+                args: " pubkey",
+                short: "Fail unless the top archive was signed by the key with this public half, as 64 hex chars. Leaves the stack as it was.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--empty",
+                        "--file",
+                        "key.hex",
+                        EXAMPLE_KEY,
+                        "--export",
+                        "./out",
+                        "--empty",
+                        "--sign",
+                        "./out/key.hex",
+                        "--verify-sig",
+                        EXAMPLE_PUBKEY,
+                    ],
+                    as_ops: vec![
+                        Op::Empty,
+                        Op::File("key.hex".into(), EXAMPLE_KEY.into()),
+                        Op::Export("./out".into()),
+                        Op::Empty,
+                        Op::Sign("./out/key.hex".into()),
+                        Op::VerifySig(EXAMPLE_PUBKEY.into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?
+                            .file("key.hex", EXAMPLE_KEY)?
+                            .export("./out")?
+                            .empty()?
+                            .sign("./out/key.hex")?
+                            .verify_sig(EXAMPLE_PUBKEY)?;
+                        assert_eq!(ctx.stack, vec![empty_digest()]);
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Dedup => OpDoc {
+                flag: "--dedup",
+                args: "",
+                short: "Report files in the top archive with identical contents, and how much space they'd free as one copy. Leaves the stack as it was.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec![
+                        "--empty", "--file", "a.txt", "same", "--file", "b.txt", "same", "--dedup",
+                    ],
+                    as_ops: vec![
+                        Op::Empty,
+                        Op::File("a.txt".into(), "same".into()),
+                        Op::File("b.txt".into(), "same".into()),
+                        Op::Dedup,
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?
+                            .file("a.txt", "same")?
+                            .file("b.txt", "same")?
+                            .dedup()?;
+                        assert!(ctx.log.recorded().0.contains("4 bytes reclaimable across 1 groups"));
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::List => OpDoc {
+                flag: "--list",
+                args: "",
+                short: "Print each entry of the top archive as a line of JSON, with its path, type, size, mode and digest. Leaves the stack as it was.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--import", ".", "fixture", "--list"],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::List,
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?.list()?;
+                        assert!(ctx
+                            .log
+                            .recorded()
+                            .0
+                            .contains(r#"{"path":"fixture/dir1","type":"dir","size":null,"#));
+                        Ok(())
+                    },
+                }],
+            },
+            // Left out of `OpCode::iter()`, so this is never shown.
+            OpCode::Fused => OpDoc {
+                flag: "",
+                args: "",
+                short: "Runs of path-only ops, made by `op::fuse`.",
+                examples: vec![],
+            },
+            OpCode::Labels => OpDoc {
+                flag: "--labels",
+                args: "",
+                short: "List every label and the archive digest it points to.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--empty", "--tag", "@nothing", "--labels"],
+                    as_ops: vec![Op::Empty, Op::Tag("@nothing".into()), Op::Labels],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.empty()?.tag("@nothing")?.labels()?;
+                        assert!(ctx.log.recorded().0.contains("@nothing -> "));
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Extract => OpDoc {
+                flag: "--extract",
+                args: " path",
+                short: "Write one file from the top archive to stdout, like `tar -xO`. Use --log-file to keep the op log out of the way.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--import", ".", "fixture", "--extract", "fixture/file_at_root.txt"],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::Extract("fixture/file_at_root.txt".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?
+                            .extract("fixture/file_at_root.txt")?;
+                        assert!(ctx.log.recorded().0.contains("some file contents"));
+                        Ok(())
+                    },
+                }],
+            },
+            OpCode::Verify => OpDoc {
+                flag: "--verify",
+                args: "",
+                short: "Re-hash every file in the top archive, failing if any blob is corrupt or missing.",
+                examples: vec![ExamplePipeline {
+                    as_txt: vec!["--import", ".", "fixture", "--verify", "--export", "./out"],
+                    as_ops: vec![
+                        Op::Import {
+                            base: ".".into(),
+                            targets: vec!["fixture".into()],
+                        },
+                        Op::Verify,
+                        Op::Export("./out".into()),
+                    ],
+                    as_ctx: &|ctx: &mut Context| {
+                        ctx.import(".", ["fixture"])?.verify()?.export("./out")?;
+                        assert!(Path::new("./out/fixture/file_at_root.txt").exists());
+                        Ok(())
+                    },
+                }],
+            },
         }
     }
 }
 
+const GLOBAL_FLAGS: &str = "Global flags:
+
+  --no-cache: Don't read from or write to the step cache.
+  --cache-limit bytes: Evict old cache entries past this size.
+  --max-download bytes: Fail any download bigger than this.
+  --concurrency N: Copy up to N files at once. Defaults to one per core.
+  --log-file path: Append the op log to a file instead of stdout.
+  --jail dir: Refuse to export anywhere outside of dir.
+  --manifest path: Write a JSON list of exported files and their digests.
+  --reproducible: Normalize permissions and attr order on import.
+  --full-metadata: Also record owner, group and mtime on import.
+  --skip-hidden: Leave dotfiles and dotdirs out of imports.
+  --include-root: Also record an imported dir's own mode, for export to restore.
+  --max-depth N: Only import this many levels of dirs deep.
+  --error-on-special: Fail imports that find a fifo, socket or device, instead of skipping it.
+  --keep-going: Skip unreadable files and dirs on import, with a warning, instead of failing.
+  --mime: Tag imported files with a MIME type based on extension.
+  --json: Log each step as a line of JSON, then the final stack.
+  --full-digests: Log whole digests instead of the first 12 hex chars.
+  --timing: Print how long each step took to stderr.
+  --dry-run: List each step as cached or not, without running any.
+  --watch: Rebuild whenever an imported path changes, until killed.
+
+";
+
 pub fn usage() -> String {
     let mut sections: Vec<&str> = vec![];
     sections.push("Usage: dirtabase [op...]\n\n");
+    sections.push(GLOBAL_FLAGS);
     sections.push("Valid ops:\n\n");
 
     for oc in OpCode::iter() {
@@ -282,6 +836,29 @@ mod test {
             usage().starts_with(indoc! {"
             Usage: dirtabase [op...]
 
+            Global flags:
+
+              --no-cache: Don't read from or write to the step cache.
+              --cache-limit bytes: Evict old cache entries past this size.
+              --max-download bytes: Fail any download bigger than this.
+              --concurrency N: Copy up to N files at once. Defaults to one per core.
+              --log-file path: Append the op log to a file instead of stdout.
+              --jail dir: Refuse to export anywhere outside of dir.
+              --manifest path: Write a JSON list of exported files and their digests.
+              --reproducible: Normalize permissions and attr order on import.
+              --full-metadata: Also record owner, group and mtime on import.
+              --skip-hidden: Leave dotfiles and dotdirs out of imports.
+              --include-root: Also record an imported dir's own mode, for export to restore.
+              --max-depth N: Only import this many levels of dirs deep.
+              --error-on-special: Fail imports that find a fifo, socket or device, instead of skipping it.
+              --keep-going: Skip unreadable files and dirs on import, with a warning, instead of failing.
+              --mime: Tag imported files with a MIME type based on extension.
+              --json: Log each step as a line of JSON, then the final stack.
+              --full-digests: Log whole digests instead of the first 12 hex chars.
+              --timing: Print how long each step took to stderr.
+              --dry-run: List each step as cached or not, without running any.
+              --watch: Rebuild whenever an imported path changes, until killed.
+
             Valid ops:
 
             --empty: Push an empty archive to the stack.
@@ -289,7 +866,7 @@ mod test {
                 Examples:
                   dirtabase --empty
 
-            --import: Copy directories into the DB as archives.
+            --import: Copy directories or files into the DB as archives. Globs like 'pkgs/*' give one archive per match.
                 Usage: --import base [target...]
                 Examples:
                   dirtabase --import . fixture
@@ -0,0 +1,146 @@
+use arkive::{Digest, IPR};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Failures worth telling apart when embedding dirtabase.
+///
+/// Ops still return `io::Error`, so these travel wrapped inside one (with a
+/// matching `ErrorKind`). Use `DirtabaseError::find` to get them back out.
+#[derive(Debug)]
+pub enum DirtabaseError {
+    /// Content didn't hash to the digest it was pinned to.
+    HashMismatch { expected: Digest, actual: Digest },
+
+    /// A blob isn't in the CAS. `path` is the archive entry that wanted it,
+    /// if it was a file rather than the archive itself.
+    DigestNotFound { digest: Digest, path: Option<IPR> },
+
+    /// A shell command exited unsuccessfully. No code if it was killed.
+    CommandFailed { cmd: String, code: Option<i32> },
+
+    /// Fetching a URL failed before we got to check what came back.
+    Download { url: String, source: reqwest::Error },
+
+    /// A download went past `--max-download`, so it was thrown away.
+    DownloadTooLarge { url: String, limit: u64 },
+
+    /// A `--filter` or `--rename` pattern isn't a valid regex.
+    InvalidPattern(regex::Error),
+
+    /// An archive's signature doesn't match it, see `--verify-sig`.
+    BadSignature { digest: Digest },
+
+    /// Stored blobs no longer match their digests, see `--verify`.
+    Corrupt { total: usize, problems: Vec<String> },
+
+    /// The DB was written by a newer dirtabase, see `db::open`.
+    UnsupportedFormat { found: u32, supported: u32 },
+}
+
+impl DirtabaseError {
+    /// Dig one of these out of an `io::Error`, if that's what it wraps.
+    pub fn find(err: &Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::HashMismatch { .. } | Self::Corrupt { .. } | Self::BadSignature { .. } => {
+                ErrorKind::InvalidData
+            }
+            Self::DigestNotFound { .. } => ErrorKind::NotFound,
+            Self::InvalidPattern(_) => ErrorKind::InvalidInput,
+            Self::DownloadTooLarge { .. } => ErrorKind::FileTooLarge,
+            Self::UnsupportedFormat { .. } => ErrorKind::Unsupported,
+            Self::CommandFailed { .. } | Self::Download { .. } => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for DirtabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::HashMismatch { expected, actual } => write!(
+                f,
+                "Hash check failed. Expected {}, got {}",
+                expected.to_hex(),
+                actual.to_hex()
+            ),
+            Self::DigestNotFound { digest, path: None } => {
+                write!(f, "Missing CAS blob {}", digest.to_hex())
+            }
+            Self::DigestNotFound {
+                digest,
+                path: Some(path),
+            } => write!(
+                f,
+                "Missing CAS blob {} for path {:?}",
+                digest.to_hex(),
+                path.as_ref()
+            ),
+            Self::CommandFailed { cmd, code } => {
+                write!(f, "Command {:?} failed with status {:?}", cmd, code)
+            }
+            Self::Download { url, source } => write!(f, "Failed to download {}: {}", url, source),
+            Self::DownloadTooLarge { url, limit } => {
+                write!(f, "Download of {} is over the {} byte limit", url, limit)
+            }
+            Self::InvalidPattern(e) => write!(f, "Invalid pattern: {}", e),
+            Self::BadSignature { digest } => {
+                write!(f, "Signature check failed for archive {}", digest.to_hex())
+            }
+            Self::Corrupt { total, problems } => write!(
+                f,
+                "{} of {} files failed verification:\n  {}",
+                problems.len(),
+                total,
+                problems.join("\n  ")
+            ),
+            Self::UnsupportedFormat { found, supported } => write!(
+                f,
+                "Store format v{} not supported, this dirtabase only reads up to v{}",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DirtabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Download { source, .. } => Some(source),
+            Self::InvalidPattern(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<DirtabaseError> for Error {
+    fn from(e: DirtabaseError) -> Self {
+        Error::new(e.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let err: Error = DirtabaseError::HashMismatch {
+            expected: Digest::from("a"),
+            actual: Digest::from("b"),
+        }
+        .into();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            DirtabaseError::find(&err),
+            Some(DirtabaseError::HashMismatch { .. })
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("Hash check failed. Expected ca978112"));
+
+        assert!(DirtabaseError::find(&Error::other("plain")).is_none());
+    }
+}
@@ -0,0 +1,522 @@
+use crate::ark::{ArkExt, StoredArk};
+use crate::attrs::AttrsExt;
+use crate::db::DBExt;
+use crate::digest::DigestExt;
+use crate::pool;
+use crate::scan::{scan_with, ScanOptions};
+use arkive::*;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::create_dir_all;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Write a stored archive out to `dest`, replacing whatever was there.
+///
+/// The archive is written next to `dest` first and swapped in with renames,
+/// so a failed export leaves the old `dest` in place. Recorded owners and
+/// mtimes are put back too, see `restore_metadata` for what `warn` gets.
+///
+/// Returns the archive that was written, for `write_manifest`.
+pub fn export(
+    db: &DB,
+    digest: &Digest,
+    dest: impl AsRef<Path>,
+    warn: impl FnMut(String),
+) -> Result<Ark<Digest>> {
+    let dest = dest.as_ref();
+    let parent = dest
+        .parent()
+        .ok_or_else(|| Error::other("Can't create tempdir to the side of output location"))?;
+
+    let ark: Ark<Digest> = Ark::load_checked(db, digest)?;
+    let tmp = tempfile::tempdir_in(parent)?;
+    ark.write(db, &tmp)?;
+    restore_metadata(&ark, &tmp, warn)?;
+
+    if dest.exists() {
+        std::fs::rename(dest, parent.join(".tmp-remove-me"))?;
+        std::fs::rename(&tmp, dest)?;
+        std::fs::remove_dir_all(parent.join(".tmp-remove-me"))?;
+    } else {
+        std::fs::rename(&tmp, dest)?;
+    }
+    Ok(ark)
+}
+
+/// One file in a manifest, see `write_manifest`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub digest: String,
+    pub mode: Option<u32>,
+}
+
+/// Write a JSON object describing every file in an exported archive to
+/// `path`, for whatever consumes the export downstream.
+///
+/// Keys are the files' paths relative to the export dest, in sorted order.
+/// Each maps to the hex digest of its contents and its `UNIX_MODE`, if the
+/// archive recorded one. Dirs aren't listed.
+pub fn write_manifest(ark: &Ark<Digest>, path: impl AsRef<Path>) -> Result<()> {
+    let mut manifest = BTreeMap::new();
+    for (ipr, attrs, digest) in ark.files() {
+        let entry = ManifestEntry {
+            digest: digest.to_hex(),
+            mode: attrs.unix_mode()?,
+        };
+        manifest.insert(ipr.as_ref().to_owned(), entry);
+    }
+    let mut json = serde_json::to_string_pretty(&manifest)?;
+    json.push('\n');
+    std::fs::write(path, json)
+}
+
+/// Write an archive into a directory, leaving anything else there alone.
+///
+/// Unlike a normal export, `dest` doesn't get swapped out wholesale, so we
+/// can't lean on one big rename. Instead each file is copied next to where
+/// it's going and renamed into place. If something fails partway, every file
+/// is either the old version or the new one, never half of each. Up to
+/// `threads` files are copied at once.
+pub fn merge_into(
+    db: &DB,
+    ark: &Ark<Digest>,
+    dest: impl AsRef<Path>,
+    threads: usize,
+) -> Result<()> {
+    let dest = dest.as_ref();
+    create_dir_all(dest)?;
+    let files: Vec<_> = ark.files().collect();
+    pool::run(
+        threads,
+        &files,
+        |_, (ipr, attrs, digest)| {
+            let dest_file = dest.join(ipr.as_ref());
+            let parent = dest_file.parent().unwrap_or(dest);
+            create_dir_all(parent)?;
+
+            let mut staged = tempfile::NamedTempFile::new_in(parent)?;
+            let mut blob = std::fs::File::open(db.cas_path(digest))?;
+            std::io::copy(&mut blob, &mut staged)?;
+            // Temp files start out 0600, whatever the archive says.
+            if let Some(mode) = attrs.unix_mode()? {
+                let perms = std::fs::Permissions::from_mode(mode & 0o7777);
+                staged.as_file().set_permissions(perms)?;
+            }
+            staged.persist(&dest_file)?;
+            Ok(())
+        },
+        |_, _| {},
+    )?;
+    // Not ark.dirs(), which walks files too (backwards) in this arkive.
+    for ipr in &ark.paths()[ark.len_files()..] {
+        create_dir_all(dest.join(ipr.as_ref()))?;
+    }
+    Ok(())
+}
+
+/// Check that `dir` holds exactly what a stored archive says it should.
+///
+/// The dir is scanned and hashed back, then compared entry by entry. Only
+/// attrs the archive recorded are compared, minus any named in `ignore` (for
+/// things an export doesn't put back). Returns one line per problem, sorted,
+/// so an empty list means they match.
+pub fn verify_export(
+    db: &DB,
+    digest: &Digest,
+    dir: impl AsRef<Path>,
+    ignore: &[&str],
+) -> Result<Vec<String>> {
+    let expected: Ark<Digest> = Ark::load_checked(db, digest)?;
+    let opts = ScanOptions {
+        full_metadata: true,
+        include_root: expected.get("") == Some(Contents::Dir),
+        ..ScanOptions::default()
+    };
+    let actual = scan_with(dir, &opts)?
+        .try_translate(|_, path| Digest::from_reader(std::fs::File::open(path)?))?;
+
+    let mut found: HashMap<&IPR, (&Attrs, Contents<&Digest>)> = actual
+        .iter()
+        .map(|(ipr, attrs, contents)| (ipr, (attrs, contents)))
+        .collect();
+    let mut problems = vec![];
+    for (ipr, attrs, contents) in expected.iter() {
+        let path = ipr.as_ref();
+        let Some((found_attrs, found_contents)) = found.remove(ipr) else {
+            problems.push(format!("{}: missing", path));
+            continue;
+        };
+        if found_contents != contents {
+            problems.push(format!("{}: contents differ", path));
+        }
+        for name in attrs.diff(found_attrs) {
+            if attrs.get(&name).is_some() && !ignore.contains(&name.as_str()) {
+                problems.push(format!("{}: attr {} differs", path, name));
+            }
+        }
+    }
+    for ipr in found.keys() {
+        problems.push(format!("{}: unexpected", ipr.as_ref()));
+    }
+    problems.sort();
+    Ok(problems)
+}
+
+/// Put back the owner, group and mtime recorded by `ScanOptions::full_metadata`,
+/// and the permissions of dirs (files already get theirs when written, by
+/// `Ark::write` or `merge_into`).
+///
+/// Changing owners usually takes root, so a refused chown goes to `warn`
+/// instead of failing the export. Entries without these attrs are left alone.
+/// A root entry (see `ScanOptions::include_root`) applies to `dest` itself.
+pub fn restore_metadata<C>(
+    ark: &Ark<C>,
+    dest: impl AsRef<Path>,
+    mut warn: impl FnMut(String),
+) -> Result<()> {
+    let dest = dest.as_ref();
+    // Children before parents, so a read-only dir doesn't get in the way.
+    let len_files = ark.len_files();
+    for (n, (ipr, attrs)) in ark.paths().iter().zip(ark.attrs().iter()).enumerate().rev() {
+        let path = dest.join(ipr.as_ref());
+        if n >= len_files {
+            if let Some(mode) = attrs.unix_mode()? {
+                let perms = std::fs::Permissions::from_mode(mode & 0o7777);
+                std::fs::set_permissions(&path, perms)?;
+            }
+        }
+        let uid = parse_attr::<u32>(attrs, "UNIX_UID")?;
+        let gid = parse_attr::<u32>(attrs, "UNIX_GID")?;
+        if uid.is_some() || gid.is_some() {
+            let meta = std::fs::symlink_metadata(&path)?;
+            let changed =
+                uid.is_some_and(|u| u != meta.uid()) || gid.is_some_and(|g| g != meta.gid());
+            if changed {
+                if let Err(e) = std::os::unix::fs::chown(&path, uid, gid) {
+                    if e.kind() != ErrorKind::PermissionDenied {
+                        return Err(e);
+                    }
+                    warn(format!("Can't chown {:?}: {}", ipr, e));
+                }
+            }
+        }
+        if let Some(mtime) = attrs.get("UNIX_MTIME") {
+            filetime::set_file_mtime(&path, parse_mtime(mtime)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_attr<T: std::str::FromStr>(attrs: &Attrs, name: &str) -> Result<Option<T>> {
+    attrs
+        .get(name)
+        .map(|v| {
+            v.parse().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Bad {} value: {:?}", name, v),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// `UNIX_MTIME` is `seconds.nanoseconds`, like `1700000000.000000123`.
+fn parse_mtime(value: &str) -> Result<FileTime> {
+    let bad = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Bad UNIX_MTIME value: {:?}", value),
+        )
+    };
+    let (secs, nanos) = value.split_once('.').ok_or_else(bad)?;
+    Ok(FileTime::from_unix_time(
+        secs.parse().map_err(|_| bad())?,
+        nanos.parse().map_err(|_| bad())?,
+    ))
+}
+
+/// Resolve an export destination, making sure it lands inside `root`.
+///
+/// For build servers running recipes they don't fully trust. `..` is
+/// resolved before checking, and so are symlinks in whatever part of the
+/// path already exists, so neither can walk back out. The root itself is
+/// off limits too, since a normal export would replace it wholesale.
+pub fn jailed(root: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<PathBuf> {
+    let root = root.as_ref().canonicalize()?;
+    let mut resolved = std::env::current_dir()?;
+    for part in dest.as_ref().components() {
+        match part {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            other => resolved.push(other),
+        }
+    }
+
+    // Canonicalize the deepest part that exists, then put the rest back.
+    let mut existing = resolved.as_path();
+    let mut rest = vec![];
+    while !existing.exists() {
+        rest.push(existing.file_name().expect("/ always exists"));
+        existing = existing.parent().expect("/ always exists");
+    }
+    let mut resolved = existing.canonicalize()?;
+    resolved.extend(rest.iter().rev());
+
+    if resolved == root || !resolved.starts_with(&root) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Export to {:?} is outside the jail {:?}",
+                dest.as_ref(),
+                root
+            ),
+        ));
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_export() -> Result<()> {
+        let db = DB::new_temp()?;
+        let digest = Ark::scan("fixture")?.import(&db)?;
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("out");
+        export(&db, &digest, &dest, |w| panic!("{}", w))?;
+
+        let perms = std::fs::Permissions::from_mode(0o600);
+        std::fs::set_permissions(dest.join("file_at_root.txt"), perms)?;
+        let problems = super::verify_export(&db, &digest, &dest, &[])?;
+        assert!(problems.contains(&"file_at_root.txt: attr UNIX_MODE differs".to_owned()));
+        assert!(!problems
+            .iter()
+            .any(|p| p.starts_with("dir1/dir2/nested.txt")));
+        assert_eq!(
+            super::verify_export(&db, &digest, &dest, &["UNIX_MODE"])?,
+            Vec::<String>::new()
+        );
+
+        std::fs::write(dest.join("file_at_root.txt"), "truncat")?;
+        std::fs::write(dest.join("extra.txt"), "")?;
+        std::fs::remove_dir_all(dest.join("dir1/dir2"))?;
+        assert_eq!(
+            super::verify_export(&db, &digest, &dest, &["UNIX_MODE"])?,
+            vec![
+                "dir1/dir2/nested.txt: missing",
+                "dir1/dir2: missing",
+                "extra.txt: unexpected",
+                "file_at_root.txt: contents differ",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn export_replaces() -> Result<()> {
+        let db = DB::new_temp()?;
+        let digest = Ark::scan("fixture")?.import(&db)?;
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("out");
+        std::fs::create_dir(&dest)?;
+        std::fs::write(dest.join("stale.txt"), "old")?;
+
+        export(&db, &digest, &dest, |w| panic!("{}", w))?;
+        assert_eq!(Ark::scan(&dest)?.paths(), Ark::scan("fixture")?.paths());
+        assert!(!dest.join("stale.txt").exists());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn manifest() -> Result<()> {
+        let db = DB::new_temp()?;
+        let digest = Ark::scan("fixture")?.import(&db)?;
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("out");
+        let ark = export(&db, &digest, &dest, |w| panic!("{}", w))?;
+        write_manifest(&ark, dir.path().join("manifest.json"))?;
+
+        let text = std::fs::read_to_string(dir.path().join("manifest.json"))?;
+        let manifest: BTreeMap<String, ManifestEntry> = serde_json::from_str(&text)?;
+        let on_disk = Ark::scan(&dest)?;
+        assert_eq!(
+            manifest.keys().collect::<Vec<_>>(),
+            on_disk
+                .files()
+                .map(|(ipr, _, _)| ipr.as_ref())
+                .collect::<Vec<_>>()
+        );
+        for (path, entry) in &manifest {
+            let file = std::fs::File::open(dest.join(path))?;
+            assert_eq!(entry.digest, Digest::from_reader(file)?.to_hex());
+            let meta = std::fs::metadata(dest.join(path))?;
+            assert_eq!(entry.mode, Some(meta.mode()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn export_root_mode() -> Result<()> {
+        let db = DB::new_temp()?;
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("private");
+        std::fs::create_dir_all(src.join("sub"))?;
+        std::fs::write(src.join("sub/key"), "secret")?;
+        std::fs::set_permissions(src.join("sub"), std::fs::Permissions::from_mode(0o750))?;
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o700))?;
+
+        let opts = ScanOptions {
+            include_root: true,
+            ..ScanOptions::default()
+        };
+        let digest = scan_with(&src, &opts)?.import(&db)?;
+        let dest = dir.path().join("restored");
+        export(&db, &digest, &dest, |w| panic!("{}", w))?;
+
+        let mode = |p: &Path| Ok::<_, Error>(std::fs::metadata(p)?.mode() & 0o7777);
+        assert_eq!(mode(&dest)?, 0o700);
+        assert_eq!(mode(&dest.join("sub"))?, 0o750);
+        assert_eq!(
+            super::verify_export(&db, &digest, &dest, &[])?,
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn merge_keeps_untracked() -> Result<()> {
+        let db = DB::new_temp()?;
+        let ark = Ark::scan("fixture")?.import_files(&db)?;
+        let script = Ark::from_entries([(
+            "run.sh",
+            Attrs::new().set_unix_mode(0o100755),
+            Contents::File(b"#!/bin/sh\n".to_vec()),
+        )])
+        .import_files(&db)?;
+        let ark = crate::ark::overlay(ark, script);
+
+        let dest = tempfile::tempdir()?;
+        let dest = dest.path();
+        std::fs::write(dest.join("untracked.txt"), "mine")?;
+        std::fs::write(dest.join("file_at_root.txt"), "old")?;
+
+        merge_into(&db, &ark, dest, 4)?;
+        for (ipr, attrs, _) in ark.files() {
+            let mode = std::fs::metadata(dest.join(ipr.as_ref()))?.mode();
+            assert_eq!(Some(mode), attrs.unix_mode()?, "{:?}", ipr);
+        }
+        let mode = std::fs::metadata(dest.join("run.sh"))?.mode();
+        assert_eq!(mode & 0o7777, 0o755);
+        assert_eq!(std::fs::read_to_string(dest.join("untracked.txt"))?, "mine");
+        assert_eq!(
+            std::fs::read_to_string(dest.join("file_at_root.txt"))?,
+            std::fs::read_to_string("fixture/file_at_root.txt")?
+        );
+        assert!(dest.join("dir1/dir2/nested.txt").exists());
+
+        // No temp files left lying around
+        let names: Vec<_> = std::fs::read_dir(dest)?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<Result<_>>()?;
+        assert_eq!(names.len(), 4, "{:?}", names);
+        Ok(())
+    }
+
+    #[test]
+    fn restore_round_trip() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        std::fs::create_dir(src.path().join("dir"))?;
+        std::fs::write(src.path().join("dir/file.txt"), "hi")?;
+        let old = FileTime::from_unix_time(1_000_000_000, 123);
+        filetime::set_file_mtime(src.path().join("dir/file.txt"), old)?;
+        filetime::set_file_mtime(src.path().join("dir"), old)?;
+
+        let opts = crate::scan::ScanOptions {
+            full_metadata: true,
+            ..Default::default()
+        };
+        let db = DB::new_temp()?;
+        let digest = crate::scan::scan_with(src.path(), &opts)?.import(&db)?;
+        let ark: Ark<Digest> = Ark::load(&db, &digest)?;
+
+        let dest = tempfile::tempdir()?;
+        ark.write(&db, dest.path().join("out"))?;
+        let mut warnings = vec![];
+        restore_metadata(&ark, dest.path().join("out"), |w| warnings.push(w))?;
+        assert_eq!(warnings, Vec::<String>::new());
+
+        for path in ["dir", "dir/file.txt"] {
+            let before = std::fs::metadata(src.path().join(path))?;
+            let after = std::fs::metadata(dest.path().join("out").join(path))?;
+            assert_eq!(FileTime::from_last_modification_time(&after), old);
+            assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn restore_chown_best_effort() -> Result<()> {
+        let dest = tempfile::tempdir()?;
+        std::fs::write(dest.path().join("file.txt"), "hi")?;
+        let ark = Ark::from_entries([(
+            "file.txt",
+            Attrs::new()
+                .append("UNIX_UID", "4242")
+                .append("UNIX_GID", "4242"),
+            Contents::File(()),
+        )]);
+
+        let mut warnings = vec![];
+        restore_metadata(&ark, dest.path(), |w| warnings.push(w))?;
+        let meta = std::fs::metadata(dest.path().join("file.txt"))?;
+        if warnings.is_empty() {
+            // We're root, so it was allowed
+            assert_eq!((meta.uid(), meta.gid()), (4242, 4242));
+        } else {
+            assert!(warnings[0].starts_with("Can't chown"), "{:?}", warnings);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn jail() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("jail");
+        std::fs::create_dir_all(root.join("out"))?;
+        std::os::unix::fs::symlink("/etc", root.join("etc"))?;
+        let root = root.canonicalize()?;
+
+        assert_eq!(jailed(&root, root.join("out"))?, root.join("out"));
+        assert_eq!(
+            jailed(&root, root.join("new/../out/deeper"))?,
+            root.join("out/deeper")
+        );
+        for escape in [
+            root.join("../../etc/passwd"),
+            root.join("out/../../elsewhere"),
+            root.join("etc/passwd"),
+            root.clone(),
+            "/etc".into(),
+        ] {
+            let err = jailed(&root, &escape).expect_err(&format!("{:?} escapes", escape));
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bad_mtime() {
+        assert!(parse_mtime("1000000000.000000005").is_ok());
+        assert!(parse_mtime("yesterday").is_err());
+        assert!(parse_mtime("1000000000").is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::ark::ArkExt;
+use crate::op::Op;
+use arkive::Ark;
+use glob::{MatchOptions, Pattern};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Wildcards stay within one name, and only match a leading `.` if the
+/// pattern spells it out, like a shell.
+const SHELL_LIKE: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true,
+};
+
+/// Does this import target need expanding, or is it a plain path?
+pub fn is_pattern(target: &str) -> bool {
+    target.contains(['*', '?', '['])
+}
+
+/// Expand every target, keeping plain paths as they are.
+pub fn expand_all(base: impl AsRef<Path>, targets: &[String]) -> Result<Vec<String>> {
+    let mut out = vec![];
+    for target in targets {
+        out.extend(expand(base.as_ref(), target)?);
+    }
+    Ok(out)
+}
+
+/// Expand the targets of every import in a pipeline, once, up front.
+///
+/// After this, each target is one archive, so `Op::stats` can count them
+/// without going near the disk.
+pub fn expand_imports(pipeline: Vec<Op>) -> Result<Vec<Op>> {
+    pipeline
+        .into_iter()
+        .map(|op| match op {
+            Op::Import { base, targets } => Ok(Op::Import {
+                targets: expand_all(&base, &targets)?,
+                base,
+            }),
+            op => Ok(op),
+        })
+        .collect()
+}
+
+/// Find what a glob matches under `base`, as sorted paths relative to it.
+///
+/// This is `glob::glob_with` with shell-like options, see `SHELL_LIKE`. A
+/// `**` component matches any number of nested dirs. A plain path comes
+/// back unchanged, existing or not. A pattern that matches nothing is a
+/// `NotFound` error, rather than quietly importing nothing.
+pub fn expand(base: impl AsRef<Path>, target: &str) -> Result<Vec<String>> {
+    if !is_pattern(target) {
+        return Ok(vec![target.to_owned()]);
+    }
+    let base = base.as_ref();
+    let pattern = Pattern::new(target).map_err(|e| bad_pattern(target, e))?;
+    let base_str = base
+        .to_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Import base isn't unicode"))?;
+    let full = format!("{}/{}", Pattern::escape(base_str), target);
+
+    // The walk itself can't skip dotfiles without missing `.cache` for
+    // `.c*`, so that rule is left to `pattern`.
+    let walk_opts = MatchOptions {
+        require_literal_leading_dot: false,
+        ..SHELL_LIKE
+    };
+    let mut found = vec![];
+    for path in glob::glob_with(&full, walk_opts).map_err(|e| bad_pattern(target, e))? {
+        let path = path.map_err(Error::from)?;
+        // glob drops a leading `./`, so a base of `.` may not be there.
+        let rel = path.strip_prefix(base).unwrap_or(&path);
+        let Some(rel) = rel.to_str() else {
+            continue;
+        };
+        // `.*` matches these, but nobody means to import the parent dir.
+        if rel.split('/').any(|name| name == "." || name == "..") {
+            continue;
+        }
+        if pattern.matches_with(rel, SHELL_LIKE) {
+            found.push(rel.to_owned());
+        }
+    }
+    found.sort();
+    found.dedup();
+    if found.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Pattern {:?} didn't match anything in {:?}", target, base),
+        ));
+    }
+    Ok(found)
+}
+
+/// A glob for whole archive paths, like `build/**` or `**/*.o`.
+///
+/// Same syntax as `expand`, but it runs against paths rather than the disk,
+/// so there's no dotfile rule: `build/**` matches `build/.stamp` too.
+pub fn path_pattern(pattern: &str) -> Result<Pattern> {
+    Pattern::new(pattern).map_err(|e| bad_pattern(pattern, e))
+}
+
+/// Does an archive path match a `path_pattern`?
+pub fn matches_path(pattern: &Pattern, path: &str) -> bool {
+    pattern.matches_with(
+        path,
+        MatchOptions {
+            require_literal_leading_dot: false,
+            ..SHELL_LIKE
+        },
+    )
+}
+
+/// Keep only the entries matching any of `patterns`, plus the dirs above them.
+///
+/// That's how a command's declared `--inputs` and `--outputs` apply, so
+/// `build/**` keeps `build` and its mode as well as what's in it.
+pub fn keep_matching<C>(ark: Ark<C>, patterns: &[String]) -> Result<Ark<C>>
+where
+    C: Clone,
+{
+    let patterns = patterns
+        .iter()
+        .map(|p| path_pattern(p))
+        .collect::<Result<Vec<_>>>()?;
+    let matched: Vec<&str> = ark
+        .paths()
+        .iter()
+        .map(|p| p.as_ref())
+        .filter(|p| patterns.iter().any(|g| matches_path(g, p)))
+        .collect();
+    let mut keep: HashSet<String> = HashSet::new();
+    for path in matched {
+        keep.insert(path.to_owned());
+        keep.extend(path.match_indices('/').map(|(n, _)| path[..n].to_owned()));
+    }
+    Ok(ark.filter(|p, _| keep.contains(p.as_ref())))
+}
+
+fn bad_pattern(pattern: &str, e: glob::PatternError) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Bad pattern {:?}: {}", pattern, e),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree() -> Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        for d in ["pkgs/a/src", "pkgs/b", "pkgs/.cache", "docs"] {
+            std::fs::create_dir_all(dir.path().join(d))?;
+        }
+        for f in ["pkgs/a/x.txt", "pkgs/b/y.md", "notes.txt"] {
+            std::fs::write(dir.path().join(f), f)?;
+        }
+        Ok(dir)
+    }
+
+    #[test]
+    fn expand() -> Result<()> {
+        let dir = tree()?;
+        let cases: [(&str, Vec<&str>); 7] = [
+            ("pkgs/*", vec!["pkgs/a", "pkgs/b"]),
+            ("pkgs/.*", vec!["pkgs/.cache"]),
+            ("pkgs/.c*", vec!["pkgs/.cache"]),
+            ("*.txt", vec!["notes.txt"]),
+            ("pkgs/?/*.[tm][xd]*", vec!["pkgs/a/x.txt", "pkgs/b/y.md"]),
+            ("pkgs/**", vec!["pkgs/a", "pkgs/a/src", "pkgs/b"]),
+            ("**/src", vec!["pkgs/a/src"]),
+        ];
+        for (pattern, want) in cases {
+            assert_eq!(super::expand(dir.path(), pattern)?, want, "{}", pattern);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn plain_paths() -> Result<()> {
+        let dir = tree()?;
+        assert_eq!(super::expand(dir.path(), "docs")?, vec!["docs"]);
+        assert_eq!(super::expand(dir.path(), "missing")?, vec!["missing"]);
+        assert_eq!(
+            expand_all(dir.path(), &["docs".into(), "pkgs/[!a]".into()])?,
+            vec!["docs", "pkgs/b"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expand_imports() -> Result<()> {
+        let dir = tree()?;
+        let base = dir.path().to_str().unwrap().to_owned();
+        let pipeline = vec![
+            Op::Import {
+                base: base.clone(),
+                targets: vec!["docs".into(), "pkgs/*".into()],
+            },
+            Op::Flatten,
+        ];
+        assert_eq!(
+            super::expand_imports(pipeline)?,
+            vec![
+                Op::Import {
+                    base,
+                    targets: vec!["docs".into(), "pkgs/a".into(), "pkgs/b".into()],
+                },
+                Op::Flatten,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn path_pattern() -> Result<()> {
+        let cases = [
+            ("build/**", "build/.stamp", true),
+            ("build/**", "build/a/b.o", true),
+            ("build/**", "builder/a", false),
+            ("**/*.o", "a.o", true),
+            ("**/*.o", "src/x/a.o", true),
+            ("**/*.o", "src/a.c", false),
+            ("src/*.c", "src/x/a.c", false),
+            ("**", "anything/at/all", true),
+        ];
+        for (pattern, path, want) in cases {
+            let compiled = super::path_pattern(pattern)?;
+            assert_eq!(matches_path(&compiled, path), want, "{} {}", pattern, path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keep_matching() -> Result<()> {
+        use arkive::{Attrs, Contents};
+        let ark = Ark::from_entries([
+            ("build", Attrs::new(), Contents::Dir),
+            ("build/bin", Attrs::new(), Contents::Dir),
+            ("build/bin/app", Attrs::new(), Contents::File(1)),
+            ("build/tmp", Attrs::new(), Contents::Dir),
+            ("scratch", Attrs::new(), Contents::File(2)),
+        ]);
+        let kept = super::keep_matching(ark, &["build/bin/*".into()])?;
+        assert_eq!(kept.paths(), &vec!["build/bin/app", "build", "build/bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn no_match() -> Result<()> {
+        let dir = tree()?;
+        let err = super::expand(dir.path(), "pkgs/*.rs").expect_err("Nothing matches");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = super::expand(dir.path(), "pkgs/[a").expect_err("Unclosed class");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+}
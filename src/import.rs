@@ -0,0 +1,395 @@
+use crate::ark::StoredArk;
+use crate::db::DBExt;
+use crate::digest::hash_copy;
+use crate::pool;
+use arkive::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Result;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Trees with at most this many files can be read into memory to import.
+const SMALL_FILES: usize = 64;
+
+/// ...and at most this many bytes in total.
+const SMALL_BYTES: u64 = 1024 * 1024;
+
+/// Is this cheaper to `read()` into memory than to import from disk?
+///
+/// In-memory contents know their digests up front, so blobs the CAS already
+/// has are skipped without staging anything. That's a lot of tiny imports
+/// (tests, config dirs). The digest comes out the same either way.
+pub fn is_small(ark: &Ark<PathBuf>) -> bool {
+    if ark.contents().len() > SMALL_FILES {
+        return false;
+    }
+    let mut bytes = 0;
+    for path in ark.contents() {
+        match std::fs::metadata(path) {
+            Ok(meta) => bytes += meta.len(),
+            // Let the real import report it.
+            Err(_) => return false,
+        }
+    }
+    bytes <= SMALL_BYTES
+}
+
+/// Import variants that let you watch the work happen.
+///
+/// These also stream each file through the hasher as it's copied, so memory
+/// use stays flat no matter how big the files are. There's no need to `read()`
+/// an `Ark<PathBuf>` into memory first. Up to `threads` files are copied at
+/// once, see `pool::run`.
+pub trait ImportExt {
+    /// Like `import_files`, but calls `progress(done, total)` as files are
+    /// hashed and stored.
+    fn import_files_with_progress(
+        self,
+        db: &DB,
+        threads: usize,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Ark<Digest>>;
+
+    /// Like `import`, but with a progress callback. See above.
+    fn import_with_progress(
+        self,
+        db: &DB,
+        threads: usize,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Digest>
+    where
+        Self: Sized,
+    {
+        self.import_files_with_progress(db, threads, progress)?
+            .store(db)
+    }
+}
+
+/// File contents that can be copied into the CAS.
+pub trait Source {
+    /// Copy to `dest`, hashing along the way.
+    ///
+    /// Should only ever hold a buffer's worth of the file in memory, so huge
+    /// files import fine on small machines.
+    fn stage(&self, dest: &Path) -> Result<Digest>;
+
+    /// Identity of the underlying file, if it's hardlinked.
+    ///
+    /// That's the only time two entries can share one, so nothing else needs
+    /// to answer.
+    fn inode(&self) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// The digest, if it's cheap to know without staging.
+    ///
+    /// Lets us skip writing anything when the CAS already has the blob.
+    fn digest(&self) -> Option<Digest> {
+        None
+    }
+
+    /// Something that changes whenever the contents might have.
+    ///
+    /// The digest gets remembered under this, so importing the same file
+    /// again (like after an interrupted import) doesn't re-read it.
+    fn stat_key(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Source for PathBuf {
+    fn stage(&self, dest: &Path) -> Result<Digest> {
+        hash_copy(File::open(self)?, File::create(dest)?)
+    }
+
+    fn inode(&self) -> Option<(u64, u64)> {
+        let meta = std::fs::metadata(self).ok()?;
+        (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+    }
+
+    fn stat_key(&self) -> Option<String> {
+        // Same trick as git's index. Any write bumps ctime, even one that
+        // puts the old mtime back.
+        let meta = std::fs::metadata(self).ok()?;
+        Some(format!(
+            "{}:{}:{}:{}.{}:{}.{}",
+            meta.dev(),
+            meta.ino(),
+            meta.size(),
+            meta.mtime(),
+            meta.mtime_nsec(),
+            meta.ctime(),
+            meta.ctime_nsec()
+        ))
+    }
+}
+
+impl Source for Vec<u8> {
+    fn stage(&self, dest: &Path) -> Result<Digest> {
+        std::fs::write(dest, self)?;
+        Ok(Digest::from(self))
+    }
+
+    fn digest(&self) -> Option<Digest> {
+        Some(Digest::from(self))
+    }
+}
+
+impl<C> ImportExt for Ark<C>
+where
+    C: Source + Sync,
+{
+    fn import_files_with_progress(
+        self,
+        db: &DB,
+        threads: usize,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Ark<Digest>> {
+        let (paths, attrs, contents) = self.decompose();
+        let (digests, _) = store_all(db, &contents, threads, progress)?;
+        Ok(Ark::compose(paths, attrs, Rc::new(digests)))
+    }
+}
+
+/// Copy contents into the CAS, returning digests and how many were hashed.
+///
+/// Hardlinks to an inode that's already being stored reuse its digest without
+/// another read, which adds up in things like package caches. Blobs the CAS
+/// already has aren't written again, and if the source knows its digest up
+/// front, aren't even staged.
+///
+/// Each blob is in the CAS as soon as it's hashed, and unchanged files are
+/// remembered by `stat_key`. So an import that gets killed halfway can just
+/// be run again, and only the files it didn't get to are read.
+fn store_all<C>(
+    db: &DB,
+    contents: &[C],
+    threads: usize,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<(Vec<Digest>, usize)>
+where
+    C: Source + Sync,
+{
+    let dir = db.tempdir()?;
+    let total = contents.len();
+
+    // Each job is the index of one content to stage, and every content
+    // knows which job's digest it'll get.
+    let mut jobs: Vec<usize> = vec![];
+    let mut job_of: Vec<usize> = Vec::with_capacity(total);
+    let mut seen: HashMap<(u64, u64), usize> = HashMap::new();
+    for (n, c) in contents.iter().enumerate() {
+        let job = match c.inode() {
+            Some(i) => *seen.entry(i).or_insert(jobs.len()),
+            None => jobs.len(),
+        };
+        if job == jobs.len() {
+            jobs.push(n);
+        }
+        job_of.push(job);
+    }
+    let mut covers = vec![0; jobs.len()];
+    for &job in &job_of {
+        covers[job] += 1;
+    }
+
+    let mut stored: Vec<Option<Digest>> = vec![None; jobs.len()];
+    let mut done = 0;
+    pool::run(
+        threads,
+        &jobs,
+        |_, &n| {
+            if let Some(digest) = contents[n].digest().filter(|d| db.exists(d)) {
+                return Ok(digest);
+            }
+            let key = contents[n].stat_key();
+            if let Some(digest) = key.as_deref().and_then(|k| recall(db, k)) {
+                return Ok(digest);
+            }
+            let temp = dir.path().join(n.to_string());
+            let digest = contents[n].stage(&temp)?;
+            if db.exists(&digest) {
+                std::fs::remove_file(temp)?;
+            } else {
+                std::fs::rename(temp, db.cas_path(&digest))?;
+            }
+            if let Some(key) = key {
+                remember(db, dir.path(), &key, &digest)?;
+            }
+            Ok(digest)
+        },
+        |job, digest| {
+            stored[job] = Some(digest);
+            done += covers[job];
+            progress(done, total);
+        },
+    )?;
+    let digests = job_of
+        .iter()
+        .map(|&job| stored[job].expect("pool::run finished every job"))
+        .collect();
+    Ok((digests, jobs.len()))
+}
+
+fn stat_path(db: &DB, key: &str) -> PathBuf {
+    db.join("stat").join(Digest::from(key).to_hex())
+}
+
+/// A digest remembered for this `stat_key`, if its blob is still around.
+fn recall(db: &DB, key: &str) -> Option<Digest> {
+    let hex = std::fs::read_to_string(stat_path(db, key)).ok()?;
+    Digest::from_hex(hex.trim())
+        .ok()
+        .filter(|digest| db.exists(digest))
+}
+
+fn remember(db: &DB, tmp: &Path, key: &str, digest: &Digest) -> Result<()> {
+    let dest = stat_path(db, key);
+    std::fs::create_dir_all(dest.parent().expect("under stat/"))?;
+
+    // Rename into place, so a crash can't leave half a digest behind.
+    let temp = tmp.join(format!("{}.stat", Digest::from(key).to_hex()));
+    std::fs::write(&temp, digest.to_hex())?;
+    std::fs::rename(temp, dest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn import_with_progress() -> Result<()> {
+        let db = DB::new_temp()?;
+        let mut calls = vec![];
+        let digest = Ark::scan("fixture")?.import_with_progress(&db, 1, |done, total| {
+            calls.push((done, total));
+        })?;
+
+        assert_eq!(calls, vec![(1, 2), (2, 2)]);
+        assert_eq!(digest, Ark::scan("fixture")?.import(&db)?);
+        Ok(())
+    }
+
+    #[test]
+    fn hardlinks_hashed_once() -> Result<()> {
+        let db = DB::new_temp()?;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a"), "shared")?;
+        std::fs::hard_link(dir.path().join("a"), dir.path().join("b"))?;
+        std::fs::write(dir.path().join("c"), "shared")?;
+
+        let ark = Ark::scan(dir.path())?;
+        for threads in [1, 4] {
+            let (digests, hashed) = store_all(&db, ark.contents(), threads, |_, _| {})?;
+            assert_eq!(digests, vec![Digest::from("shared"); 3]);
+            assert_eq!(hashed, 2); // Not 3, since b is a
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn already_stored() -> Result<()> {
+        let db = DB::new_temp()?;
+        let ark = Ark::from_entries([("a", Contents::File(b"same".to_vec()))]);
+        for _ in 0..2 {
+            let (digests, _) = store_all(&db, ark.contents(), 1, |_, _| {})?;
+            assert_eq!(digests, vec![Digest::from("same")]);
+        }
+        assert_eq!(std::fs::read_dir(db.join("cas"))?.count(), 1);
+
+        // Written once, then left alone. A rename over it would swap inodes.
+        let first = Ark::scan("fixture")?.import_files_with_progress(&db, 1, |_, _| {})?;
+        let blob = db.cas_path(&first.contents()[0]);
+        let inode = std::fs::metadata(&blob)?.ino();
+        Ark::scan("fixture")?.import_files_with_progress(&db, 1, |_, _| {})?;
+        assert_eq!(std::fs::metadata(&blob)?.ino(), inode);
+        assert_eq!(std::fs::read_dir(db.join("cas"))?.count(), 3);
+        assert_eq!(std::fs::read_dir(db.join("tmp"))?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn resume() -> Result<()> {
+        let db = DB::new_temp()?;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a"), "first")?;
+        let ark = Ark::scan(dir.path())?;
+        let (digests, _) = store_all(&db, ark.contents(), 1, |_, _| {})?;
+        assert_eq!(digests, vec![Digest::from("first")]);
+
+        // Unchanged file: the remembered digest is trusted, not re-read.
+        let key = ark.contents()[0].stat_key().expect("on disk");
+        let decoy = Ark::from_entries([("x", Contents::File(b"decoy".to_vec()))]);
+        store_all(&db, decoy.contents(), 1, |_, _| {})?;
+        std::fs::write(stat_path(&db, &key), Digest::from("decoy").to_hex())?;
+        let (digests, _) = store_all(&db, ark.contents(), 1, |_, _| {})?;
+        assert_eq!(digests, vec![Digest::from("decoy")]);
+
+        // Changed file: new key, so it's hashed again.
+        std::fs::write(dir.path().join("a"), "second")?;
+        let (digests, _) = store_all(&db, ark.contents(), 1, |_, _| {})?;
+        assert_eq!(digests, vec![Digest::from("second")]);
+
+        // Remembered blob went missing: hashed again too.
+        std::fs::remove_file(db.cas_path(&Digest::from("second")))?;
+        let (digests, _) = store_all(&db, ark.contents(), 1, |_, _| {})?;
+        assert_eq!(digests, vec![Digest::from("second")]);
+        assert!(db.exists(&Digest::from("second")));
+        Ok(())
+    }
+
+    #[test]
+    fn small() -> Result<()> {
+        let ark = Ark::scan("fixture")?;
+        assert!(is_small(&ark));
+        let db = DB::new_temp()?;
+        let from_disk = ark.clone().import_with_progress(&db, 1, |_, _| {})?;
+        let from_memory = ark.read()?.import_with_progress(&db, 1, |_, _| {})?;
+        assert_eq!(from_disk, from_memory);
+
+        let dir = tempfile::tempdir()?;
+        for n in 0..=SMALL_FILES {
+            std::fs::write(dir.path().join(n.to_string()), "")?;
+        }
+        assert!(!is_small(&Ark::scan(dir.path())?));
+        std::fs::write(dir.path().join("big"), vec![0; SMALL_BYTES as usize + 1])?;
+        assert!(!is_small(&crate::scan::scan(dir.path().join("big"))?));
+        Ok(())
+    }
+
+    #[test]
+    fn threaded_same_digest() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for n in 0..40 {
+            std::fs::write(dir.path().join(n.to_string()), n.to_string())?;
+        }
+        let serial = Ark::scan(dir.path())?.import_with_progress(&DB::new_temp()?, 1, |_, _| {})?;
+
+        let mut calls = vec![];
+        let threaded =
+            Ark::scan(dir.path())?.import_with_progress(&DB::new_temp()?, 8, |done, total| {
+                calls.push((done, total));
+            })?;
+        assert_eq!(serial, threaded);
+        assert_eq!(calls, (1..=40).map(|n| (n, 40)).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn stage() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let big: Vec<u8> = (0..200_000u32).map(|n| n as u8).collect();
+        for contents in [vec![], b"small".to_vec(), big] {
+            let src = dir.path().join("src");
+            let dest = dir.path().join("dest");
+            std::fs::write(&src, &contents)?;
+
+            assert_eq!(src.stage(&dest)?, Digest::from(&contents));
+            assert_eq!(std::fs::read(&dest)?, contents);
+            assert_eq!(contents.stage(&dest)?, Digest::from(&contents));
+        }
+        Ok(())
+    }
+}
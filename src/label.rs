@@ -0,0 +1,150 @@
+use arkive::{Digest, DB};
+use std::io::{Error, ErrorKind, Result, Write};
+
+/// A human-friendly name for an archive, like `@release-1.0`.
+///
+/// Labels are files in the DB's `labels/` dir, containing the hex digest of
+/// the archive they point to. The name is used directly as a filename, so it
+/// has to be validated to keep it from pointing anywhere else.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
+pub struct Label(String);
+
+#[derive(Debug, PartialEq)]
+pub enum InvalidLabel {
+    Empty,
+    PathSeparator(String),
+    Reserved(String),
+}
+impl From<InvalidLabel> for Error {
+    fn from(il: InvalidLabel) -> Self {
+        Self::new(
+            ErrorKind::InvalidInput,
+            match il {
+                InvalidLabel::Empty => "Label names can't be empty".to_owned(),
+                InvalidLabel::PathSeparator(name) => {
+                    format!("Label {:?} can't contain path separators", name)
+                }
+                InvalidLabel::Reserved(name) => format!("Label {:?} is a reserved name", name),
+            },
+        )
+    }
+}
+
+/// Prefix for half-written label files. Never a valid label name.
+const TEMP_PREFIX: &str = "tmp.";
+
+impl Label {
+    pub fn new(name: impl AsRef<str>) -> std::result::Result<Self, InvalidLabel> {
+        let name = name.as_ref();
+        if name.is_empty() {
+            Err(InvalidLabel::Empty)
+        } else if name.contains(['/', '\\', '\0']) {
+            Err(InvalidLabel::PathSeparator(name.to_owned()))
+        } else if name == "." || name == ".." || name.starts_with(TEMP_PREFIX) {
+            Err(InvalidLabel::Reserved(name.to_owned()))
+        } else {
+            Ok(Self(name.to_owned()))
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Point this label at an archive, replacing any previous value.
+    pub fn write(&self, db: &DB, digest: &Digest) -> Result<()> {
+        let dir = db.join("labels");
+        std::fs::create_dir_all(&dir)?;
+
+        // Write to the side and rename, so readers never see half a digest.
+        let mut tmp = tempfile::Builder::new()
+            .prefix(TEMP_PREFIX)
+            .tempfile_in(&dir)?;
+        tmp.write_all(digest.to_hex().as_bytes())?;
+        tmp.persist(dir.join(&self.0))?;
+        Ok(())
+    }
+
+    /// Get the digest this label points to, if the label exists.
+    pub fn read(&self, db: &DB) -> Result<Option<Digest>> {
+        let text = match std::fs::read_to_string(db.join("labels").join(&self.0)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let digest = Digest::from_hex(text.trim()).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Label {:?} doesn't contain a digest: {}", self.0, e),
+            )
+        })?;
+        Ok(Some(digest))
+    }
+}
+
+/// All labels in the DB, sorted by name.
+///
+/// Skips anything in the labels dir that isn't a valid label name, including
+/// temp files from in-progress writes.
+pub fn list(db: &DB) -> Result<Vec<Label>> {
+    let dir = db.join("labels");
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut labels = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(label) = name.to_str().and_then(|n| Label::new(n).ok()) {
+            labels.push(label);
+        }
+    }
+    labels.sort();
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new() {
+        assert_eq!(Label::new("@foo"), Ok(Label("@foo".into())));
+        assert_eq!(Label::new(""), Err(InvalidLabel::Empty));
+        assert_eq!(Label::new(".."), Err(InvalidLabel::Reserved("..".into())));
+        assert_eq!(
+            Label::new("tmp.abc"),
+            Err(InvalidLabel::Reserved("tmp.abc".into()))
+        );
+        assert_eq!(
+            Label::new("../../etc/passwd"),
+            Err(InvalidLabel::PathSeparator("../../etc/passwd".into()))
+        );
+    }
+
+    #[test]
+    fn read_write() -> Result<()> {
+        let db = DB::new_temp()?;
+        let label = Label::new("@foo")?;
+        assert_eq!(label.read(&db)?, None);
+
+        let digest = Digest::from("some archive");
+        label.write(&db, &digest)?;
+        assert_eq!(label.read(&db)?, Some(digest));
+        Ok(())
+    }
+
+    #[test]
+    fn list_labels() -> Result<()> {
+        let db = DB::new_temp()?;
+        assert_eq!(list(&db)?, vec![]);
+
+        let digest = Digest::from("some archive");
+        Label::new("@b")?.write(&db, &digest)?;
+        Label::new("@a")?.write(&db, &digest)?;
+        std::fs::write(db.join("labels").join("tmp.leftover"), "junk")?;
+
+        assert_eq!(list(&db)?, vec![Label::new("@a")?, Label::new("@b")?]);
+        Ok(())
+    }
+}
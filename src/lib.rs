@@ -1,8 +1,24 @@
+pub mod ark;
+pub mod attrs;
 pub mod behavior;
 pub mod cli;
+pub mod config;
+pub mod contents;
 pub mod context;
+pub mod db;
+pub mod digest;
 pub mod doc;
+pub mod error;
+pub mod export;
+pub mod glob;
+pub mod import;
+pub mod label;
 pub mod logger;
+pub mod mime;
 pub mod op;
+pub mod pool;
 pub mod prelude;
+pub mod scan;
+pub mod sign;
 pub(crate) mod test_tools;
+pub mod watch;
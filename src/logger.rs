@@ -1,4 +1,6 @@
-use std::io::{self, Result, Write};
+use std::fs::File;
+use std::io::{self, LineWriter, Result, Write};
+use std::path::Path;
 
 /// Controls where logs for a specific channel are routed to
 #[derive(Copy, Clone)]
@@ -6,6 +8,8 @@ pub enum Policy {
     Stdout,
     Stderr,
     Silent,
+    /// Whatever file the Logger was given with `open_file`.
+    File,
 }
 
 pub struct Policies {
@@ -17,6 +21,15 @@ pub struct Policies {
 
     /// Whether to print "--- [some shell command] ---" before running commands.
     pub cmd: Policy,
+
+    /// "imported X/N files" updates during big imports.
+    pub progress: Policy,
+
+    /// How long each op took, for finding the slow step in a pipeline.
+    pub timing: Policy,
+
+    /// Things that went wrong without failing the op, like a refused chown.
+    pub warning: Policy,
 }
 
 impl Default for Policies {
@@ -25,6 +38,9 @@ impl Default for Policies {
             opheader: Policy::Stdout,
             stack: Policy::Stdout,
             cmd: Policy::Stdout,
+            progress: Policy::Stderr,
+            timing: Policy::Silent,
+            warning: Policy::Stderr,
         }
     }
 }
@@ -33,6 +49,7 @@ pub enum WriteBackend {
     RealStdout(io::Stdout),
     RealStderr(io::Stderr),
     ByteVector(Vec<u8>),
+    File(LineWriter<File>),
     Silent,
 }
 impl WriteBackend {
@@ -51,6 +68,7 @@ impl Write for WriteBackend {
             Self::RealStdout(w) => w.write(bytes),
             Self::RealStderr(w) => w.write(bytes),
             Self::ByteVector(w) => w.write(bytes),
+            Self::File(w) => w.write(bytes),
             Self::Silent => Ok(bytes.len()),
         }
     }
@@ -60,6 +78,7 @@ impl Write for WriteBackend {
             Self::RealStdout(w) => w.flush(),
             Self::RealStderr(w) => w.flush(),
             Self::ByteVector(w) => w.flush(),
+            Self::File(w) => w.flush(),
             Self::Silent => Ok(()),
         }
     }
@@ -85,6 +104,9 @@ pub struct Logger {
     pub stderr: WriteBackend,
     pub pol: Policies,
 
+    /// Shared by every channel with `Policy::File`, so lines don't interleave.
+    file: WriteBackend,
+
     // Exists for dumb workaround reasons
     silent: WriteBackend,
 }
@@ -94,6 +116,7 @@ impl Logger {
         Self {
             stdout: stdout.into(),
             stderr: stderr.into(),
+            file: WriteBackend::Silent,
             silent: WriteBackend::Silent,
             pol: Policies::default(),
         }
@@ -116,11 +139,21 @@ impl Logger {
         )
     }
 
+    /// Open a file (appending) for `Policy::File` channels to write to.
+    ///
+    /// Until this is called, those channels are silent.
+    pub fn open_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let f = File::options().create(true).append(true).open(path)?;
+        self.file = WriteBackend::File(LineWriter::new(f));
+        Ok(())
+    }
+
     fn wb_for(&mut self, pol: Policy) -> &mut WriteBackend {
         match pol {
             Policy::Stdout => &mut self.stdout,
             Policy::Stderr => &mut self.stderr,
             Policy::Silent => &mut self.silent,
+            Policy::File => &mut self.file,
         }
     }
 
@@ -133,6 +166,15 @@ impl Logger {
     pub fn cmd(&mut self) -> &mut WriteBackend {
         self.wb_for(self.pol.cmd)
     }
+    pub fn progress(&mut self) -> &mut WriteBackend {
+        self.wb_for(self.pol.progress)
+    }
+    pub fn timing(&mut self) -> &mut WriteBackend {
+        self.wb_for(self.pol.timing)
+    }
+    pub fn warning(&mut self) -> &mut WriteBackend {
+        self.wb_for(self.pol.warning)
+    }
 }
 
 pub fn vec_logger() -> Logger {
@@ -190,4 +232,24 @@ mod test {
         assert_eq!(log.stderr.recorded().unwrap(), "");
         Ok(())
     }
+
+    #[test]
+    fn test_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("log.txt");
+        let mut log = vec_logger();
+        log.pol.opheader = Policy::File;
+        log.pol.stack = Policy::File;
+
+        writeln!(log.opheader(), "Not open yet")?;
+        log.open_file(&path)?;
+        writeln!(log.opheader(), "header")?;
+        writeln!(log.stack(), "stack")?;
+        writeln!(log.cmd(), "cmd")?;
+        log.opheader().flush()?;
+
+        assert_eq!(std::fs::read_to_string(&path)?, "header\nstack\n");
+        assert_eq!(log.stdout.recorded().unwrap(), "cmd\n");
+        Ok(())
+    }
 }
@@ -1,11 +1,27 @@
 #![allow(dead_code)]
+mod ark;
+mod attrs;
 mod behavior;
 mod cli;
+mod config;
+mod contents;
 mod context;
+mod db;
+mod digest;
 mod doc;
+mod error;
+mod export;
+mod glob;
+mod import;
+mod label;
 mod logger;
+mod mime;
 mod op;
+mod pool;
+mod scan;
+mod sign;
 pub(crate) mod test_tools;
+mod watch;
 
 fn main() -> std::process::ExitCode {
     crate::cli::real_cli()
@@ -0,0 +1,75 @@
+/// What we call a file when we can't tell.
+pub const UNKNOWN: &str = "application/octet-stream";
+
+/// Common extensions, with web content in mind. Lowercase, sorted.
+const BY_EXTENSION: &[(&str, &str)] = &[
+    ("avif", "image/avif"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("md", "text/markdown"),
+    ("mjs", "text/javascript"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("ogg", "audio/ogg"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("toml", "application/toml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("zip", "application/zip"),
+];
+
+/// Guess a MIME type from the extension at the end of a path.
+pub fn guess(path: &str) -> &'static str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let ext = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ext.to_ascii_lowercase(),
+        _ => return UNKNOWN,
+    };
+    match BY_EXTENSION.binary_search_by(|(e, _)| (*e).cmp(ext.as_str())) {
+        Ok(n) => BY_EXTENSION[n].1,
+        Err(_) => UNKNOWN,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sorted() {
+        assert!(BY_EXTENSION.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn test_guess() {
+        assert_eq!(guess("index.html"), "text/html");
+        assert_eq!(guess("site/img/LOGO.PNG"), "image/png");
+        assert_eq!(guess("archive.tar.gz"), "application/gzip");
+        assert_eq!(guess("Makefile"), UNKNOWN);
+        assert_eq!(guess("dir.d/README"), UNKNOWN);
+        assert_eq!(guess(".bashrc"), UNKNOWN);
+        assert_eq!(guess("weird.xyz"), UNKNOWN);
+    }
+}
@@ -1,14 +1,38 @@
+use crate::ark::MergeStrategy;
+use crate::label::{InvalidLabel, Label};
 use arkive::Digest;
 use hex::FromHexError;
 use serde::Serialize;
+use std::num::ParseIntError;
 use strum_macros::EnumIter;
 
 #[derive(Debug, PartialEq)]
 pub enum ParseError {
-    MissingArg { oc: OpCode, name: &'static str },
-    TooManyArgs { oc: OpCode, excess: usize },
+    MissingArg {
+        oc: OpCode,
+        name: &'static str,
+    },
+    TooManyArgs {
+        oc: OpCode,
+        excess: usize,
+    },
     ArgBeforeFirstOp(String),
     InvalidDigest(String, FromHexError),
+    MissingFlagArg {
+        flag: &'static str,
+        name: &'static str,
+    },
+    InvalidNumber(String, ParseIntError),
+    InvalidLabel(InvalidLabel),
+    InvalidPrefixMap(String),
+    InvalidRename {
+        pattern: String,
+        replacement: String,
+        reason: String,
+    },
+    NotAFlag(OpCode),
+    InvalidStrategy(String),
+    NotAfterCmd(OpCode),
 }
 impl From<ParseError> for std::io::Error {
     fn from(pe: ParseError) -> Self {
@@ -26,11 +50,40 @@ impl From<ParseError> for std::io::Error {
                     arg, err
                 )
             }
+            ParseError::MissingFlagArg { flag, name } => {
+                format!("Flag {} missing arg {}", flag, name)
+            }
+            ParseError::InvalidNumber(arg, err) => {
+                format!("Arg {:?} could not be parsed as a number: {}", arg, err)
+            }
+            ParseError::InvalidLabel(il) => std::io::Error::from(il).to_string(),
+            ParseError::InvalidPrefixMap(arg) => {
+                format!("Arg {:?} should look like old=new,old2=new2", arg)
+            }
+            ParseError::InvalidRename {
+                pattern,
+                replacement,
+                reason,
+            } => format!(
+                "Can't rename {:?} to {:?}: {}",
+                pattern, replacement, reason
+            ),
+            ParseError::InvalidStrategy(arg) => format!(
+                "Arg {:?} should be last-wins, first-wins or merge-attrs",
+                arg
+            ),
+            ParseError::NotAfterCmd(oc) => format!(
+                "Op {:?} has to come right after --cmd-impure or --cmd-cached",
+                oc
+            ),
+            ParseError::NotAFlag(oc) => {
+                format!("Op {:?} is internal and can't be parsed from args", oc)
+            }
         })
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, EnumIter)]
+#[derive(Debug, PartialEq, Clone, Copy, EnumIter, Serialize)]
 pub enum OpCode {
     Empty,
     Import,
@@ -38,24 +91,81 @@ pub enum OpCode {
     Merge,
     Prefix,
     Rename,
+    RenameAll,
     Filter,
     Download,
     DownloadImpure,
     CmdImpure,
+    CmdCached,
+    Inputs,
+    Outputs,
+    Flatten,
+    ExportMerge,
+    File,
+    Verify,
+    Tag,
+    Labels,
+    Extract,
+    PrefixMap,
+    Print,
+    Assert,
+    Sign,
+    VerifySig,
+    Dedup,
+    List,
+    /// Not a flag. Only `fuse` makes these, so it's left out of `iter()`.
+    #[strum(disabled)]
+    Fused,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize)]
 pub enum Op {
     Empty,
-    Import { base: String, targets: Vec<String> },
+    Import {
+        base: String,
+        targets: Vec<String>,
+    },
     Export(String),
-    Merge,
+    /// Merge the top N archives, or the whole stack if `None`.
+    Merge(Option<usize>, MergeStrategy),
     Prefix(String),
     Filter(String),
     Rename(String, String),
+    /// Like `Rename`, but every match in a path, not just the first.
+    RenameAll(String, String),
     Download(String, Digest),
     DownloadImpure(String),
-    CmdImpure(String),
+    /// `inputs` and `outputs` are globs, see `glob::keep_matching`. Empty
+    /// means everything.
+    CmdImpure {
+        cmd: String,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+    },
+    CmdCached {
+        cmd: String,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+    },
+    Flatten,
+    ExportMerge(String),
+    File(String, String),
+    Verify,
+    Tag(String),
+    Labels,
+    Extract(String),
+    /// (old, new) prefix pairs, see `ark::remap_prefixes`.
+    PrefixMap(Vec<(String, String)>),
+    Print,
+    Assert(Digest),
+    /// Path to a secret key file, see `sign::signing_key`.
+    Sign(String),
+    /// Public key, as hex.
+    VerifySig(String),
+    Dedup,
+    List,
+    /// Path-only ops run as one step, see `fuse`.
+    Fused(Vec<Op>),
 }
 
 impl OpCode {
@@ -79,8 +189,19 @@ impl OpCode {
                 Ok(Op::Export(dest))
             }
             Self::Merge => {
+                // Both optional, so tell them apart by what they look like
+                let mut it = it.peekable();
+                let count = it
+                    .next_if(|arg| MergeStrategy::from_arg(arg).is_none())
+                    .map(|n| n.parse().map_err(|e| ParseError::InvalidNumber(n, e)))
+                    .transpose()?;
+                let strategy = it
+                    .next()
+                    .map(|s| MergeStrategy::from_arg(&s).ok_or(ParseError::InvalidStrategy(s)))
+                    .transpose()?
+                    .unwrap_or_default();
                 no_further_params(self, &mut it)?;
-                Ok(Op::Merge)
+                Ok(Op::Merge(count, strategy))
             }
             Self::Prefix => {
                 let prefix = consume_param(self, "prefix", &mut it)?;
@@ -93,11 +214,19 @@ impl OpCode {
                 Ok(Op::Filter(pattern))
             }
             Self::Rename => {
-                let pattern = consume_param(self, "pattern", &mut it)?;
-                let replacement = consume_param(self, "replacement", &mut it)?;
+                let pattern: String = consume_param(self, "pattern", &mut it)?;
+                let replacement: String = consume_param(self, "replacement", &mut it)?;
                 no_further_params(self, &mut it)?;
+                check_replacement(&pattern, &replacement)?;
                 Ok(Op::Rename(pattern, replacement))
             }
+            Self::RenameAll => {
+                let pattern: String = consume_param(self, "pattern", &mut it)?;
+                let replacement: String = consume_param(self, "replacement", &mut it)?;
+                no_further_params(self, &mut it)?;
+                check_replacement(&pattern, &replacement)?;
+                Ok(Op::RenameAll(pattern, replacement))
+            }
             Self::Download => {
                 let url = consume_param(self, "url", &mut it)?;
                 let hash = consume_param(self, "hash", &mut it)?;
@@ -114,8 +243,126 @@ impl OpCode {
             Self::CmdImpure => {
                 let cmd = consume_param(self, "cmd", &mut it)?;
                 no_further_params(self, &mut it)?;
-                Ok(Op::CmdImpure(cmd))
+                Ok(Op::CmdImpure {
+                    cmd,
+                    inputs: vec![],
+                    outputs: vec![],
+                })
+            }
+            Self::CmdCached => {
+                let cmd = consume_param(self, "cmd", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Ok(Op::CmdCached {
+                    cmd,
+                    inputs: vec![],
+                    outputs: vec![],
+                })
+            }
+            // Only ever part of a command, see `declare`.
+            Self::Inputs | Self::Outputs => Err(ParseError::NotAfterCmd(*self)),
+            Self::Flatten => {
+                no_further_params(self, &mut it)?;
+                Ok(Op::Flatten)
+            }
+            Self::ExportMerge => {
+                let dest = consume_param(self, "dest", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Ok(Op::ExportMerge(dest))
+            }
+            Self::File => {
+                let path = consume_param(self, "path", &mut it)?;
+                let content = consume_param(self, "content", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Ok(Op::File(path, content))
+            }
+            Self::Verify => {
+                no_further_params(self, &mut it)?;
+                Ok(Op::Verify)
+            }
+            Self::Tag => {
+                let name: String = consume_param(self, "label", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Label::new(&name).map_err(ParseError::InvalidLabel)?;
+                Ok(Op::Tag(name))
+            }
+            Self::Labels => {
+                no_further_params(self, &mut it)?;
+                Ok(Op::Labels)
+            }
+            Self::Extract => {
+                let path = consume_param(self, "path", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Ok(Op::Extract(path))
+            }
+            Self::PrefixMap => {
+                let map: String = consume_param(self, "map", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Ok(Op::PrefixMap(parse_prefix_map(&map)?))
             }
+            Self::Print => {
+                no_further_params(self, &mut it)?;
+                Ok(Op::Print)
+            }
+            Self::Assert => {
+                let hash = consume_param(self, "hash", &mut it)?;
+                no_further_params(self, &mut it)?;
+                let digest =
+                    Digest::from_hex(&hash).map_err(|e| ParseError::InvalidDigest(hash, e))?;
+                Ok(Op::Assert(digest))
+            }
+            Self::Sign => {
+                let key = consume_param(self, "keyfile", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Ok(Op::Sign(key))
+            }
+            Self::VerifySig => {
+                let key = consume_param(self, "pubkey", &mut it)?;
+                no_further_params(self, &mut it)?;
+                Ok(Op::VerifySig(key))
+            }
+            Self::Dedup => {
+                no_further_params(self, &mut it)?;
+                Ok(Op::Dedup)
+            }
+            Self::List => {
+                no_further_params(self, &mut it)?;
+                Ok(Op::List)
+            }
+            Self::Fused => Err(ParseError::NotAFlag(*self)),
+        }
+    }
+
+    /// How many args this op always takes, before any optional ones.
+    ///
+    /// Those belong to the op whatever they look like, even `--json`.
+    pub fn required_args(&self) -> usize {
+        match self {
+            Self::Rename | Self::RenameAll | Self::Download | Self::File => 2,
+            Self::Import
+            | Self::Export
+            | Self::Prefix
+            | Self::Filter
+            | Self::DownloadImpure
+            | Self::CmdImpure
+            | Self::CmdCached
+            | Self::Inputs
+            | Self::Outputs
+            | Self::ExportMerge
+            | Self::Tag
+            | Self::Extract
+            | Self::PrefixMap
+            | Self::Assert
+            | Self::Sign
+            | Self::VerifySig => 1,
+            Self::Empty
+            | Self::Merge
+            | Self::Flatten
+            | Self::Verify
+            | Self::Labels
+            | Self::Print
+            | Self::Dedup
+            | Self::List
+            | Self::Fused => 0,
         }
     }
 
@@ -128,9 +375,27 @@ impl OpCode {
             "--prefix" => Some(Self::Prefix),
             "--filter" => Some(Self::Filter),
             "--rename" => Some(Self::Rename),
+            "--rename-all" => Some(Self::RenameAll),
             "--download" => Some(Self::Download),
             "--download-impure" => Some(Self::DownloadImpure),
             "--cmd-impure" => Some(Self::CmdImpure),
+            "--cmd-cached" => Some(Self::CmdCached),
+            "--inputs" => Some(Self::Inputs),
+            "--outputs" => Some(Self::Outputs),
+            "--flatten" => Some(Self::Flatten),
+            "--export-merge" => Some(Self::ExportMerge),
+            "--file" => Some(Self::File),
+            "--verify" => Some(Self::Verify),
+            "--tag" => Some(Self::Tag),
+            "--labels" => Some(Self::Labels),
+            "--extract" => Some(Self::Extract),
+            "--prefix-map" => Some(Self::PrefixMap),
+            "--print" => Some(Self::Print),
+            "--assert" => Some(Self::Assert),
+            "--sign" => Some(Self::Sign),
+            "--verify-sig" => Some(Self::VerifySig),
+            "--dedup" => Some(Self::Dedup),
+            "--list" => Some(Self::List),
             _ => None,
         }
     }
@@ -142,15 +407,85 @@ impl Op {
             Self::Empty => OpCode::Empty,
             Self::Import { .. } => OpCode::Import,
             Self::Export(_) => OpCode::Export,
-            Self::Merge => OpCode::Merge,
+            Self::Merge(_, _) => OpCode::Merge,
             Self::Prefix(_) => OpCode::Prefix,
             Self::Filter(_) => OpCode::Filter,
             Self::Rename(_, _) => OpCode::Rename,
+            Self::RenameAll(_, _) => OpCode::RenameAll,
             Self::Download(_, _) => OpCode::Download,
             Self::DownloadImpure(_) => OpCode::DownloadImpure,
-            Self::CmdImpure(_) => OpCode::CmdImpure,
+            Self::CmdImpure { .. } => OpCode::CmdImpure,
+            Self::CmdCached { .. } => OpCode::CmdCached,
+            Self::Flatten => OpCode::Flatten,
+            Self::ExportMerge(_) => OpCode::ExportMerge,
+            Self::File(_, _) => OpCode::File,
+            Self::Verify => OpCode::Verify,
+            Self::Tag(_) => OpCode::Tag,
+            Self::Labels => OpCode::Labels,
+            Self::Extract(_) => OpCode::Extract,
+            Self::PrefixMap(_) => OpCode::PrefixMap,
+            Self::Print => OpCode::Print,
+            Self::Assert(_) => OpCode::Assert,
+            Self::Sign(_) => OpCode::Sign,
+            Self::VerifySig(_) => OpCode::VerifySig,
+            Self::Dedup => OpCode::Dedup,
+            Self::List => OpCode::List,
+            Self::Fused(_) => OpCode::Fused,
         }
     }
+
+    /// Does this only move, drop or rename entries of the top archive?
+    ///
+    /// Those can all be done to an archive in memory, one after another,
+    /// without storing what's in between. See `fuse`.
+    pub fn is_path_only(&self) -> bool {
+        matches!(
+            self,
+            Self::Prefix(_)
+                | Self::Filter(_)
+                | Self::Rename(_, _)
+                | Self::RenameAll(_, _)
+                | Self::Flatten
+                | Self::PrefixMap(_)
+        )
+    }
+
+    /// Does this write its results to stdout, for piping somewhere else?
+    ///
+    /// Pipelines with one of these keep logs off stdout, see `cli::cli`.
+    pub fn writes_stdout(&self) -> bool {
+        matches!(
+            self,
+            Self::Print | Self::List | Self::Extract(_) | Self::Labels | Self::Dedup
+        )
+    }
+}
+
+/// Combine each run of path-only ops into one `Op::Fused`.
+///
+/// `--prefix a --prefix b --filter x` would otherwise load and store the
+/// archive three times. The result is the same either way, but a fused run
+/// is cached (and logged) as a single step.
+pub fn fuse(pipeline: Vec<Op>) -> Vec<Op> {
+    let mut out = vec![];
+    let mut run = vec![];
+    for op in pipeline {
+        if op.is_path_only() {
+            run.push(op);
+        } else {
+            end_run(&mut out, &mut run);
+            out.push(op);
+        }
+    }
+    end_run(&mut out, &mut run);
+    out
+}
+
+fn end_run(out: &mut Vec<Op>, run: &mut Vec<Op>) {
+    match run.len() {
+        0 | 1 => out.append(run),
+        _ => out.push(Op::Fused(std::mem::take(run))),
+    }
 }
 
 pub fn parse_pipeline<T>(args: impl IntoIterator<Item = T>) -> Result<Vec<Op>, ParseError>
@@ -158,9 +493,12 @@ where
     T: AsRef<str>,
 {
     let mut ops = Vec::<(OpCode, Vec<String>)>::new();
-    for arg in args {
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
         if let Some(oc) = OpCode::from_arg(arg.as_ref()) {
-            ops.push((oc, vec![]))
+            // Taken as-is, so `--file notes.txt --empty` is a file of "--empty"
+            let required = it.by_ref().take(oc.required_args());
+            ops.push((oc, required.map(|a| a.as_ref().into()).collect()))
         } else {
             let latest = ops
                 .last_mut()
@@ -168,7 +506,104 @@ where
             latest.1.push(arg.as_ref().into());
         }
     }
-    ops.into_iter().map(|(oc, args)| oc.to_op(args)).collect()
+    let mut pipeline = vec![];
+    for (oc, args) in ops {
+        match oc {
+            OpCode::Inputs | OpCode::Outputs => declare(&mut pipeline, oc, args)?,
+            _ => pipeline.push(oc.to_op(args)?),
+        }
+    }
+    Ok(pipeline)
+}
+
+/// Add `--inputs` or `--outputs` globs to the command just before them.
+fn declare(pipeline: &mut [Op], oc: OpCode, globs: Vec<String>) -> Result<(), ParseError> {
+    if globs.is_empty() {
+        return Err(ParseError::MissingArg {
+            oc,
+            name: "pattern",
+        });
+    }
+    let (inputs, outputs) = match pipeline.last_mut() {
+        Some(Op::CmdImpure {
+            inputs, outputs, ..
+        })
+        | Some(Op::CmdCached {
+            inputs, outputs, ..
+        }) => (inputs, outputs),
+        _ => return Err(ParseError::NotAfterCmd(oc)),
+    };
+    match oc {
+        OpCode::Inputs => inputs.extend(globs),
+        _ => outputs.extend(globs),
+    }
+    Ok(())
+}
+
+/// Split `old=new,old2=new2` into pairs. Every `old` needs to be non-empty.
+fn parse_prefix_map(arg: &str) -> Result<Vec<(String, String)>, ParseError> {
+    arg.split(',')
+        .map(|pair| match pair.split_once('=') {
+            Some((old, new)) if !old.trim_matches('/').is_empty() => {
+                Ok((old.to_owned(), new.to_owned()))
+            }
+            _ => Err(ParseError::InvalidPrefixMap(arg.to_owned())),
+        })
+        .collect()
+}
+
+/// Check that every `$1` or `$name` in a rename's replacement is a group the
+/// pattern really has.
+///
+/// The regex crate quietly puts an empty string in for one that isn't. That's
+/// never what anyone meant, especially with `$1a`, which asks for a group
+/// named `1a` rather than group 1 and then an `a` (that's `${1}a`).
+fn check_replacement(pattern: &str, replacement: &str) -> Result<(), ParseError> {
+    let invalid = |reason: String| ParseError::InvalidRename {
+        pattern: pattern.to_owned(),
+        replacement: replacement.to_owned(),
+        reason,
+    };
+    let re = regex::Regex::new(pattern).map_err(|e| invalid(e.to_string()))?;
+
+    // Same rules as `regex::Captures::expand`.
+    let mut rest = replacement;
+    while let Some(dollar) = rest.find('$') {
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+        let name = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => {
+                    rest = &braced[end + 1..];
+                    &braced[..end]
+                }
+                // Unclosed, so just a literal `$`
+                None => continue,
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len());
+                let name = &rest[..end];
+                rest = &rest[end..];
+                name
+            }
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let exists = match name.parse::<usize>() {
+            Ok(n) => n < re.captures_len(),
+            Err(_) => re.capture_names().flatten().any(|n| n == name),
+        };
+        if !exists {
+            return Err(invalid(format!("the pattern has no group {:?}", name)));
+        }
+    }
+    Ok(())
 }
 
 fn consume_param<T>(
@@ -223,6 +658,82 @@ mod test {
         assert_eq!(OpCode::from_arg("--import"), Some(OpCode::Import));
     }
 
+    #[test]
+    fn fuse() {
+        let pipeline = vec![
+            Op::Empty,
+            Op::Prefix("a".into()),
+            Op::Empty,
+            Op::Prefix("b".into()),
+            Op::Filter("x".into()),
+            Op::Flatten,
+            Op::Merge(None, MergeStrategy::LastWins),
+            Op::Flatten,
+        ];
+        assert_eq!(
+            super::fuse(pipeline),
+            vec![
+                Op::Empty,
+                Op::Prefix("a".into()),
+                Op::Empty,
+                Op::Fused(vec![
+                    Op::Prefix("b".into()),
+                    Op::Filter("x".into()),
+                    Op::Flatten
+                ]),
+                Op::Merge(None, MergeStrategy::LastWins),
+                Op::Flatten,
+            ]
+        );
+
+        // Fusing is internal, there's no way to ask for it by hand.
+        use strum::IntoEnumIterator;
+        assert!(OpCode::iter().all(|oc| oc != OpCode::Fused));
+        assert_eq!(
+            OpCode::Fused.to_op(vec!["--prefix".into(), "a".into()]),
+            Err(ParseError::NotAFlag(OpCode::Fused))
+        );
+    }
+
+    #[test]
+    fn check_replacement() {
+        for (pattern, replacement) in [
+            ("a", "b"),
+            ("(a)(b)", "$2$1"),
+            ("(a)", "${1}x"),
+            ("(?P<name>a)", "$name-${name}"),
+            ("a", "$$1"),
+            ("a", "cost: $"),
+            ("a", "${unclosed"),
+        ] {
+            assert_eq!(
+                super::check_replacement(pattern, replacement),
+                Ok(()),
+                "{:?} -> {:?}",
+                pattern,
+                replacement
+            );
+        }
+        for (pattern, replacement, missing) in [
+            ("(a)", "$2", "2"),
+            ("(a)", "$1a", "1a"),
+            ("(?P<name>a)", "${other}", "other"),
+        ] {
+            assert_eq!(
+                parse_pipeline(["--rename-all", pattern, replacement]),
+                Err(ParseError::InvalidRename {
+                    pattern: pattern.into(),
+                    replacement: replacement.into(),
+                    reason: format!("the pattern has no group {:?}", missing),
+                })
+            );
+        }
+        assert!(matches!(
+            parse_pipeline(["--rename", "(", "x"]),
+            Err(ParseError::InvalidRename { .. })
+        ));
+    }
+
     #[test]
     fn parse() {
         assert_eq!(parse_pipeline([] as [&str; 0]), Ok(vec![]));
@@ -255,6 +766,104 @@ mod test {
                 excess: 2,
             })
         );
+        assert_eq!(
+            parse_pipeline(["--empty", "--file", "Dockerfile"]),
+            Err(ParseError::MissingArg {
+                oc: OpCode::File,
+                name: "content",
+            })
+        );
+        assert_eq!(
+            parse_pipeline(["--merge", "--merge", "2"]),
+            Ok(vec![
+                Op::Merge(None, MergeStrategy::LastWins),
+                Op::Merge(Some(2), MergeStrategy::LastWins)
+            ])
+        );
+        assert!(parse_pipeline(["--merge", "all"]).is_err());
+        assert_eq!(
+            parse_pipeline([
+                "--empty",
+                "--file",
+                "notes.txt",
+                "--empty",
+                "--cmd-impure",
+                "--empty"
+            ]),
+            Ok(vec![
+                Op::Empty,
+                Op::File("notes.txt".into(), "--empty".into()),
+                Op::CmdImpure {
+                    cmd: "--empty".into(),
+                    inputs: vec![],
+                    outputs: vec![],
+                }
+            ])
+        );
+        assert_eq!(
+            parse_pipeline(["--merge", "first-wins", "--merge", "2", "merge-attrs"]),
+            Ok(vec![
+                Op::Merge(None, MergeStrategy::FirstWins),
+                Op::Merge(Some(2), MergeStrategy::MergeAttrs)
+            ])
+        );
+        assert_eq!(
+            parse_pipeline(["--merge", "2", "newest"]),
+            Err(ParseError::InvalidStrategy("newest".into()))
+        );
+        assert!(parse_pipeline(["--merge", "first-wins", "2"]).is_err());
+        assert_eq!(
+            parse_pipeline(["--empty", "--tag", "a/b"]),
+            Err(ParseError::InvalidLabel(InvalidLabel::PathSeparator(
+                "a/b".into()
+            )))
+        );
+        assert_eq!(
+            parse_pipeline([
+                "--empty",
+                "--cmd-cached",
+                "make",
+                "--inputs",
+                "src/**",
+                "Makefile",
+                "--outputs",
+                "build/**",
+                "--outputs",
+                "*.log",
+            ]),
+            Ok(vec![
+                Op::Empty,
+                Op::CmdCached {
+                    cmd: "make".into(),
+                    inputs: vec!["src/**".into(), "Makefile".into()],
+                    outputs: vec!["build/**".into(), "*.log".into()],
+                }
+            ])
+        );
+        assert_eq!(
+            parse_pipeline(["--empty", "--cmd-impure", "make", "--outputs"]),
+            Err(ParseError::MissingArg {
+                oc: OpCode::Outputs,
+                name: "pattern",
+            })
+        );
+        assert_eq!(
+            parse_pipeline(["--empty", "--outputs", "build/**"]),
+            Err(ParseError::NotAfterCmd(OpCode::Outputs))
+        );
+        assert_eq!(
+            parse_pipeline(["--prefix-map", "a=b,c/d="]),
+            Ok(vec![Op::PrefixMap(vec![
+                ("a".into(), "b".into()),
+                ("c/d".into(), "".into())
+            ])])
+        );
+        for bad in ["a", "=b", "a=b,", "/=x"] {
+            assert_eq!(
+                parse_pipeline(["--prefix-map", bad]),
+                Err(ParseError::InvalidPrefixMap(bad.into()))
+            );
+        }
         assert_eq!(
             parse_pipeline(["--empty", "--empty", "--empty"]),
             Ok(vec![Op::Empty, Op::Empty, Op::Empty])
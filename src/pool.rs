@@ -0,0 +1,117 @@
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+/// How many workers to use when nobody says otherwise.
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Run `work` on every item, with up to `threads` at once.
+///
+/// Results come back to `done` on the calling thread, in the order they
+/// finish, along with the item's index. That keeps progress callbacks and
+/// other bookkeeping single-threaded. The first error stops handing out new
+/// items and is returned once the workers in flight wind down.
+///
+/// With one thread, nothing is spawned and items run in order.
+pub fn run<T, R>(
+    threads: usize,
+    items: &[T],
+    work: impl Fn(usize, &T) -> Result<R> + Sync,
+    mut done: impl FnMut(usize, R),
+) -> Result<()>
+where
+    T: Sync,
+    R: Send,
+{
+    if threads <= 1 || items.len() <= 1 {
+        for (n, item) in items.iter().enumerate() {
+            done(n, work(n, item)?);
+        }
+        return Ok(());
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|s| {
+        for _ in 0..threads.min(items.len()) {
+            let tx = tx.clone();
+            let (next, failed, work) = (&next, &failed, &work);
+            s.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let n = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(n) else { break };
+                    let result = work(n, item);
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    if tx.send((n, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // Otherwise rx never sees the channel close.
+        drop(tx);
+
+        let mut first_err = None;
+        for (n, result) in rx {
+            match result {
+                Ok(r) if first_err.is_none() => done(n, r),
+                Ok(_) => {}
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Error;
+
+    #[test]
+    fn all_items() -> Result<()> {
+        for threads in [1, 4] {
+            let items: Vec<usize> = (0..50).collect();
+            let mut got = vec![None; items.len()];
+            run(threads, &items, |_, x| Ok(x * 2), |n, r| got[n] = Some(r))?;
+            let want: Vec<_> = items.iter().map(|x| Some(x * 2)).collect();
+            assert_eq!(got, want);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn serial_in_order() -> Result<()> {
+        let mut order = vec![];
+        run(1, &["a", "b", "c"], |_, x| Ok(*x), |_, x| order.push(x))?;
+        assert_eq!(order, vec!["a", "b", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn first_error() {
+        for threads in [1, 4] {
+            let items: Vec<usize> = (0..20).collect();
+            let err = run(
+                threads,
+                &items,
+                |_, x| match x {
+                    7 => Err(Error::other("seven")),
+                    x => Ok(*x),
+                },
+                |_, _| {},
+            )
+            .expect_err("7 fails");
+            assert_eq!(err.to_string(), "seven");
+        }
+    }
+}
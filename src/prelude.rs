@@ -1,4 +1,11 @@
-pub use crate::context::Context;
+pub use crate::ark::{empty_digest, ArkExt, StoredArk, EMPTY_ARCHIVE_DIGEST};
+pub use crate::attrs::AttrsExt;
+pub use crate::contents::ContentsExt;
+pub use crate::context::{Context, Transform};
+pub use crate::db::DBExt;
+pub use crate::digest::DigestExt;
+pub use crate::error::DirtabaseError;
+pub use crate::import::ImportExt;
 pub use crate::logger::Logger;
 pub use arkive::*;
 pub use std::io::Result;
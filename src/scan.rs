@@ -0,0 +1,513 @@
+use arkive::*;
+use std::collections::HashSet;
+use std::fs::{FileType, Metadata};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Knobs for how `scan_with` reads the disk.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ScanOptions {
+    /// Also record `UNIX_UID`, `UNIX_GID` and `UNIX_MTIME` on each entry.
+    ///
+    /// Off by default, since mtimes differ between every checkout of the same
+    /// files, and that would make otherwise-identical imports hash apart.
+    pub full_metadata: bool,
+
+    /// Leave out anything whose name starts with `.`, like `.git/`.
+    ///
+    /// Hidden dirs aren't descended into at all. The path being scanned is
+    /// always included, even if it's hidden itself.
+    pub skip_hidden: bool,
+
+    /// Record a scanned dir's own attrs too, as a dir entry at the root.
+    ///
+    /// Off by default, so that importing a dir doesn't depend on the mode
+    /// of wherever it was checked out. On for backups, where it should.
+    pub include_root: bool,
+
+    /// Don't descend more than this many dirs deep. Dirs at the limit are
+    /// still recorded, just empty, so `Some(1)` is only immediate children.
+    pub max_depth: Option<usize>,
+
+    /// What to do about fifos, sockets and devices.
+    pub special: SpecialFiles,
+
+    /// Put every entry under this path instead of at the root. Same result
+    /// as `ArkExt::prefix` afterwards, without rewriting every path twice.
+    pub prefix: String,
+
+    /// Leave out anything that can't be read, with a warning, instead of
+    /// failing the whole scan. See `scan_partial`.
+    pub keep_going: bool,
+}
+
+/// What `scan_partial` had to leave out, and why.
+pub type Problems = Vec<(PathBuf, Error)>;
+
+/// What a scan does when it finds something that's neither a regular file,
+/// a dir nor a symlink.
+///
+/// There's no way to record one faithfully: `Contents` lives upstream and
+/// only knows files and dirs, and hashing a fifo as a file would block until
+/// something writes to it.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum SpecialFiles {
+    /// Leave it out, with a warning.
+    #[default]
+    Skip,
+    /// Fail the scan.
+    Error,
+}
+
+/// Name a file type, if it's one `SpecialFiles` is about.
+fn special_kind(ft: FileType) -> Option<&'static str> {
+    if ft.is_fifo() {
+        Some("fifo")
+    } else if ft.is_socket() {
+        Some("socket")
+    } else if ft.is_block_device() {
+        Some("block device")
+    } else if ft.is_char_device() {
+        Some("char device")
+    } else {
+        None
+    }
+}
+
+/// Apply `opts.special` to `path`. `Ok(true)` means leave it out.
+fn skip_special(
+    path: &Path,
+    meta: &Metadata,
+    opts: &ScanOptions,
+    warn: &mut impl FnMut(String),
+) -> Result<bool> {
+    let Some(kind) = special_kind(meta.file_type()) else {
+        return Ok(false);
+    };
+    match opts.special {
+        SpecialFiles::Skip => {
+            warn(format!("Skipping {} {:?}", kind, path));
+            Ok(true)
+        }
+        SpecialFiles::Error => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Can't import {} {:?}", kind, path),
+        )),
+    }
+}
+
+/// Read a directory or single file from disk into an Ark.
+///
+/// Upstream `Ark::scan` only understands directories, and quietly gives you
+/// an empty archive for anything else. Here, a file becomes a one-entry
+/// archive with the file at its basename. A missing path is an error.
+pub fn scan(path: impl AsRef<Path>) -> Result<Ark<PathBuf>> {
+    scan_with(path, &ScanOptions::default())
+}
+
+/// Like `scan`, but with control over what gets recorded.
+pub fn scan_with(path: impl AsRef<Path>, opts: &ScanOptions) -> Result<Ark<PathBuf>> {
+    scan_with_warnings(path, opts, |_| {})
+}
+
+/// Like `scan_with`, but skipped special files are reported to `warn`. So
+/// is anything left out by `ScanOptions::keep_going`.
+pub fn scan_with_warnings(
+    path: impl AsRef<Path>,
+    opts: &ScanOptions,
+    mut warn: impl FnMut(String),
+) -> Result<Ark<PathBuf>> {
+    if !opts.keep_going {
+        return scan_inner(path.as_ref(), opts, &mut warn, None);
+    }
+    let mut problems = vec![];
+    let ark = scan_inner(path.as_ref(), opts, &mut warn, Some(&mut problems))?;
+    for (path, e) in problems {
+        warn(format!("Skipping {:?}: {}", path, e));
+    }
+    Ok(ark)
+}
+
+/// Like `scan_with`, but get past anything under `path` that can't be read,
+/// like a dir we don't have permission for.
+///
+/// Whatever could be read comes back as the archive, and everything else is
+/// listed next to it. Only `path` itself not existing fails outright.
+pub fn scan_partial(
+    path: impl AsRef<Path>,
+    opts: &ScanOptions,
+) -> Result<(Ark<PathBuf>, Problems)> {
+    let mut problems = vec![];
+    let ark = scan_inner(path.as_ref(), opts, &mut |_| {}, Some(&mut problems))?;
+    Ok((ark, problems))
+}
+
+/// Everything above, where `problems` is whether to keep going.
+fn scan_inner(
+    path: &Path,
+    opts: &ScanOptions,
+    warn: &mut impl FnMut(String),
+    problems: Option<&mut Problems>,
+) -> Result<Ark<PathBuf>> {
+    let meta = std::fs::metadata(path)?;
+    if meta.is_dir() {
+        let mut entries = scan_dir(path, &meta, opts, warn, problems)?;
+        if opts.include_root {
+            entries.push((
+                opts.prefix.as_str().into(),
+                attrs_for(meta, opts),
+                Contents::Dir,
+            ));
+        }
+        return Ok(Ark::from_entries(entries));
+    }
+
+    if skip_special(path, &meta, opts, warn)? {
+        return Ok(Ark::empty());
+    }
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| std::io::Error::other(format!("Can't name file {:?}", path)))?;
+    Ok(Ark::from_entries([(
+        under(&opts.prefix, Path::new(name))?,
+        attrs_for(meta, opts),
+        Contents::File(path.to_owned()),
+    )]))
+}
+
+type Entry = (IPR, Attrs, Contents<PathBuf>);
+
+/// Walk everything under `base`.
+///
+/// Uses a queue rather than recursion, so depth is only limited by memory.
+/// Symlinks are recorded, never followed. A dir we've already been inside
+/// (say, through a bind mount) is recorded but not walked again, so loops
+/// end. Order doesn't matter, `Ark::from_entries` sorts.
+fn scan_dir(
+    base: &Path,
+    base_meta: &Metadata,
+    opts: &ScanOptions,
+    warn: &mut impl FnMut(String),
+    mut problems: Option<&mut Problems>,
+) -> Result<Vec<Entry>> {
+    let mut out = vec![];
+    let mut visited: HashSet<(u64, u64)> = HashSet::new();
+    visited.insert((base_meta.dev(), base_meta.ino()));
+    let mut queue = vec![];
+    if opts.max_depth != Some(0) {
+        queue.push((base.to_owned(), 0));
+    }
+    while let Some((dir, depth)) = queue.pop() {
+        let Some(entries) = noted(std::fs::read_dir(&dir), &dir, &mut problems)? else {
+            continue;
+        };
+        for entry in entries {
+            let Some(entry) = noted(entry, &dir, &mut problems)? else {
+                continue;
+            };
+            if opts.skip_hidden && entry.file_name().as_encoded_bytes().starts_with(b".") {
+                continue;
+            }
+            let path = entry.path();
+            let Some(meta) = noted(entry.metadata(), &path, &mut problems)? else {
+                continue;
+            };
+            let rel = path.strip_prefix(base).expect("read_dir stays under base");
+            let Some(ipr) = noted(under(&opts.prefix, rel), &path, &mut problems)? else {
+                continue;
+            };
+
+            if meta.is_dir() {
+                let deeper = opts.max_depth.is_none_or(|max| depth + 1 < max);
+                if deeper && visited.insert((meta.dev(), meta.ino())) {
+                    queue.push((path, depth + 1));
+                }
+                out.push((ipr, attrs_for(meta, opts), Contents::Dir));
+            } else if !skip_special(&path, &meta, opts, warn)? {
+                out.push((ipr, attrs_for(meta, opts), Contents::File(path)));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The entry for `rel`, once it's moved under `prefix`.
+fn under(prefix: &str, rel: &Path) -> Result<IPR> {
+    let ipr: IPR = rel.try_into().map_err(Error::other)?;
+    Ok(match prefix {
+        "" => ipr,
+        _ => format!("{}/{}", prefix, ipr.as_ref()).into(),
+    })
+}
+
+/// Either note an error down in `problems` and skip past it, or stop at it
+/// if we're not keeping going.
+fn noted<T>(
+    result: Result<T>,
+    path: &Path,
+    problems: &mut Option<&mut Problems>,
+) -> Result<Option<T>> {
+    match (result, problems.as_deref_mut()) {
+        (Ok(value), _) => Ok(Some(value)),
+        (Err(e), Some(problems)) => {
+            problems.push((path.to_owned(), e));
+            Ok(None)
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+fn attrs_for(meta: Metadata, opts: &ScanOptions) -> Attrs {
+    let (uid, gid) = (meta.uid(), meta.gid());
+    let mtime = format!("{}.{:09}", meta.mtime(), meta.mtime_nsec());
+    let attrs = Attrs::from(meta);
+    if !opts.full_metadata {
+        return attrs;
+    }
+    attrs
+        .append("UNIX_UID", uid.to_string())
+        .append("UNIX_GID", gid.to_string())
+        .append("UNIX_MTIME", mtime)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ark::ArkExt;
+    use crate::attrs::AttrsExt;
+
+    #[test]
+    fn scan_dir() -> Result<()> {
+        assert_eq!(scan("fixture")?, Ark::scan("fixture")?);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_file() -> Result<()> {
+        let ark = scan("fixture/dir1/dir2/nested.txt")?;
+        let mode = std::fs::metadata("fixture/dir1/dir2/nested.txt")?;
+        assert_eq!(ark.paths(), &vec!["nested.txt"]);
+        assert_eq!(ark.attrs(), &vec![Attrs::from(mode)]);
+        assert_eq!(
+            ark.contents(),
+            &vec![PathBuf::from("fixture/dir1/dir2/nested.txt")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scan_missing() {
+        let err = scan("fixture/nope").expect_err("Should not scan");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn scan_deep() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let depth = 1000;
+        let deepest = "d/".repeat(depth);
+        std::fs::create_dir_all(dir.path().join(&deepest))?;
+        std::fs::write(dir.path().join(&deepest).join("bottom.txt"), "hi")?;
+
+        let ark = scan(dir.path())?;
+        assert_eq!(ark.len_dirs(), depth);
+        assert_eq!(ark.paths()[0], deepest + "bottom.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn scan_symlink_loop() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("a"))?;
+        std::os::unix::fs::symlink("..", dir.path().join("a/up"))?;
+
+        let ark = scan(dir.path())?;
+        assert_eq!(ark.paths(), &vec!["a/up", "a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix() -> Result<()> {
+        let opts = ScanOptions {
+            prefix: "a/b".into(),
+            include_root: true,
+            ..ScanOptions::default()
+        };
+        let plain = ScanOptions {
+            include_root: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(
+            scan_with("fixture", &opts)?,
+            scan_with("fixture", &plain)?.prefix("a/b")
+        );
+        assert_eq!(
+            scan_with("fixture/file_at_root.txt", &opts)?.paths(),
+            &vec!["a/b/file_at_root.txt"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scan_partial() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join("ok/sub"))?;
+        std::fs::write(dir.path().join("ok/sub/file"), "")?;
+        std::fs::create_dir(dir.path().join("locked"))?;
+        std::fs::write(dir.path().join("locked/secret"), "")?;
+        // Not UTF-8, so it can't be an IPR. Unlike the locked dir, this
+        // fails even when the tests run as root.
+        let bad_name = dir.path().join(std::ffi::OsStr::from_bytes(b"bad\xff"));
+        std::fs::write(&bad_name, "")?;
+        let locked = dir.path().join("locked");
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))?;
+        let as_root = std::fs::read_dir(&locked).is_ok();
+
+        assert!(scan(dir.path()).is_err());
+        let (ark, problems) = super::scan_partial(dir.path(), &ScanOptions::default())?;
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+
+        let mut expected = vec!["ok/sub/file", "locked", "ok", "ok/sub"];
+        let mut problem_paths = vec![bad_name];
+        if as_root {
+            expected.insert(0, "locked/secret");
+        } else {
+            problem_paths.push(locked);
+        }
+        assert_eq!(ark.paths(), &expected);
+        let mut found: Vec<PathBuf> = problems.into_iter().map(|(p, _)| p).collect();
+        found.sort();
+        problem_paths.sort();
+        assert_eq!(found, problem_paths);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_special() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("file"), "")?;
+        let status = std::process::Command::new("mkfifo")
+            .arg(dir.path().join("fifo"))
+            .status()?;
+        assert!(status.success());
+        let _socket = std::os::unix::net::UnixListener::bind(dir.path().join("socket"))?;
+
+        let mut warnings = vec![];
+        let ark = scan_with_warnings(dir.path(), &ScanOptions::default(), |w| warnings.push(w))?;
+        assert_eq!(ark.paths(), &vec!["file"]);
+        warnings.sort();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("Skipping fifo"));
+        assert!(warnings[1].starts_with("Skipping socket"));
+
+        let opts = ScanOptions {
+            special: SpecialFiles::Error,
+            ..ScanOptions::default()
+        };
+        let err = scan_with(dir.path(), &opts).expect_err("Has a fifo");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = scan_with(dir.path().join("fifo"), &opts).expect_err("Is a fifo");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(scan(dir.path().join("fifo"))?.paths().len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_full_metadata() -> Result<()> {
+        let opts = ScanOptions {
+            full_metadata: true,
+            ..ScanOptions::default()
+        };
+        let ark = scan_with("fixture", &opts)?;
+        assert_eq!(ark.paths(), Ark::scan("fixture")?.paths());
+
+        let meta = std::fs::metadata("fixture/file_at_root.txt")?;
+        let (_, attrs, _) = ark
+            .files()
+            .find(|(ipr, _, _)| **ipr == "file_at_root.txt")
+            .expect("scanned");
+        assert_eq!(attrs.get("UNIX_UID"), Some(meta.uid().to_string().as_str()));
+        assert_eq!(attrs.get("UNIX_GID"), Some(meta.gid().to_string().as_str()));
+        assert_eq!(
+            attrs.get("UNIX_MTIME"),
+            Some(format!("{}.{:09}", meta.mtime(), meta.mtime_nsec()).as_str())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scan_skip_hidden() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join(".git/objects"))?;
+        std::fs::create_dir(dir.path().join("src"))?;
+        std::fs::write(dir.path().join(".hidden"), "shh")?;
+        std::fs::write(dir.path().join("src/.env"), "shh")?;
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}")?;
+
+        let all = scan(dir.path())?;
+        assert_eq!(
+            all.paths(),
+            &vec![
+                ".hidden",
+                "src/.env",
+                "src/main.rs",
+                ".git",
+                ".git/objects",
+                "src"
+            ]
+        );
+
+        let opts = ScanOptions {
+            skip_hidden: true,
+            ..ScanOptions::default()
+        };
+        let visible = scan_with(dir.path(), &opts)?;
+        assert_eq!(visible.paths(), &vec!["src/main.rs", "src"]);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_max_depth() -> Result<()> {
+        let depth = |max| {
+            let opts = ScanOptions {
+                max_depth: Some(max),
+                ..ScanOptions::default()
+            };
+            scan_with("fixture", &opts)
+        };
+        assert_eq!(depth(1)?.paths(), &vec!["file_at_root.txt", "dir1"]);
+        assert_eq!(
+            depth(2)?.paths(),
+            &vec!["file_at_root.txt", "dir1", "dir1/dir2"]
+        );
+        assert_eq!(depth(0)?.paths(), &Vec::<IPR>::new());
+        assert_eq!(depth(99)?, scan("fixture")?);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_include_root() -> Result<()> {
+        let opts = ScanOptions {
+            include_root: true,
+            ..ScanOptions::default()
+        };
+        let ark = scan_with("fixture", &opts)?;
+        let (ipr, attrs) = ark.dir_at(0).expect("root sorts first");
+        assert_eq!(ipr.as_ref(), "");
+        assert_eq!(attrs, &Attrs::from(std::fs::metadata("fixture")?));
+        let rest: Vec<&IPR> = ark
+            .paths()
+            .iter()
+            .filter(|p| !p.as_ref().is_empty())
+            .collect();
+        assert_eq!(
+            rest,
+            Ark::scan("fixture")?.paths().iter().collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::error::DirtabaseError;
+use arkive::{Digest, DB};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Prefix for half-written signature files, same idea as for labels.
+const TEMP_PREFIX: &str = "tmp.";
+
+/// Signatures are detached, in the DB's `sigs/` dir, named after the archive
+/// they cover. The archive itself is untouched, so signing it doesn't change
+/// its digest.
+fn sig_path(db: &DB, digest: &Digest) -> PathBuf {
+    db.join("sigs").join(digest.to_hex())
+}
+
+/// Decode 32 bytes of hex, which is how both kinds of key are written.
+fn key_bytes(hex_key: &str, what: &str) -> Result<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(hex_key.trim(), &mut bytes).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Bad {}, expected 64 hex chars: {}", what, e),
+        )
+    })?;
+    Ok(bytes)
+}
+
+/// Read an ed25519 secret key from a file, as hex.
+///
+/// It's a file rather than an argument, so the key doesn't show up in `ps`
+/// or shell history. Any 32 random bytes will do, like from
+/// `head -c 32 /dev/urandom | xxd -p -c 32`.
+pub fn signing_key(path: impl AsRef<Path>) -> Result<SigningKey> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(SigningKey::from_bytes(&key_bytes(&text, "signing key")?))
+}
+
+/// Parse an ed25519 public key from hex.
+pub fn verifying_key(hex_key: &str) -> Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&key_bytes(hex_key, "public key")?)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Bad public key: {}", e)))
+}
+
+/// Sign an archive digest, replacing any signature it had before.
+pub fn sign(db: &DB, digest: &Digest, key: &SigningKey) -> Result<()> {
+    let sig = key.sign(digest.to_bytes());
+    let path = sig_path(db, digest);
+    let dir = path.parent().expect("sig path has a parent");
+    std::fs::create_dir_all(dir)?;
+
+    let mut tmp = tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .tempfile_in(dir)?;
+    tmp.write_all(hex::encode(sig.to_bytes()).as_bytes())?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
+/// Check that an archive was signed by the holder of `key`.
+///
+/// No signature at all is a `NotFound` error. One that doesn't check out,
+/// because either it or the archive were tampered with or it came from a
+/// different key, is a `DirtabaseError::BadSignature`.
+pub fn verify(db: &DB, digest: &Digest, key: &VerifyingKey) -> Result<()> {
+    let text = std::fs::read_to_string(sig_path(db, digest)).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => Error::new(
+            ErrorKind::NotFound,
+            format!("Archive {} isn't signed", digest.to_hex()),
+        ),
+        _ => e,
+    })?;
+    let bad = || DirtabaseError::BadSignature { digest: *digest };
+    let mut bytes = [0u8; 64];
+    hex::decode_to_slice(text.trim(), &mut bytes).map_err(|_| bad())?;
+    key.verify(digest.to_bytes(), &Signature::from_bytes(&bytes))
+        .map_err(|_| bad())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keys() -> (SigningKey, VerifyingKey) {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public = key.verifying_key();
+        (key, public)
+    }
+
+    #[test]
+    fn sign_and_verify() -> Result<()> {
+        let db = DB::new_temp()?;
+        let (key, public) = keys();
+        let digest = Digest::from("archive");
+
+        let err = verify(&db, &digest, &public).expect_err("Unsigned");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        sign(&db, &digest, &key)?;
+        verify(&db, &digest, &public)?;
+
+        // Someone else's key
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        let err = verify(&db, &digest, &other).expect_err("Wrong key");
+        assert!(matches!(
+            DirtabaseError::find(&err),
+            Some(DirtabaseError::BadSignature { .. })
+        ));
+
+        // A modified archive has a different digest, so to pass it would need
+        // the old signature moved over to it.
+        let modified = Digest::from("archive, but evil");
+        std::fs::copy(sig_path(&db, &digest), sig_path(&db, &modified))?;
+        let err = verify(&db, &modified, &public).expect_err("Tampered");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn keys_from_hex() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("key.hex");
+        std::fs::write(&path, format!("{}\n", "07".repeat(32)))?;
+        let (key, public) = keys();
+        assert_eq!(signing_key(&path)?.to_bytes(), key.to_bytes());
+        assert_eq!(verifying_key(&hex::encode(public.to_bytes()))?, public);
+
+        std::fs::write(&path, "abc")?;
+        assert_eq!(
+            signing_key(&path).expect_err("Short").kind(),
+            ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+}
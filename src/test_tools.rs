@@ -2,26 +2,139 @@ pub const REPRODUCIBLE_URL: &str = "https://gist.githubusercontent.com/MaddieM4/
 pub const REPRODUCIBLE_DIGEST: &str =
     "460f3d82bf451fbebd1958fe4714e2a82a6570dda19e0d6f39cd7504adca6088";
 
-// Todo: move into prefix op
-#[cfg(test)]
-fn prefix_ark<C>(ark: arkive::Ark<C>, prefix: &str) -> arkive::Ark<C> {
-    use arkive::ToIPR;
-    let (p, a, c) = ark.decompose();
-    let p: Vec<arkive::IPR> = p
-        .iter()
-        .map(|ipr| (prefix.to_owned() + "/" + ipr.as_ref()).to_ipr())
-        .collect();
-    arkive::Ark::compose(std::rc::Rc::new(p), a, c)
-}
-
 #[cfg(test)]
 pub fn fixture_digest() -> arkive::Digest {
     let db = arkive::DB::new_temp().expect("Temp DB");
-    let fixture_ark = prefix_ark(
-        arkive::Ark::scan("fixture").expect("Scan fixture dir"),
-        "fixture",
-    );
+    use crate::ark::ArkExt;
+    let fixture_ark = arkive::Ark::scan("fixture")
+        .expect("Scan fixture dir")
+        .prefix("fixture");
     assert_eq!(fixture_ark.len(), 4);
     let digest = fixture_ark.import(&db).expect("Imported to temp DB");
     digest
 }
+
+/// Lay out a small tree with fully pinned contents and modes.
+///
+/// Unlike `fixture/`, nothing here depends on the checkout or the umask, so
+/// archives built from it hash the same on every machine.
+#[cfg(test)]
+pub fn golden_tree(dir: &std::path::Path) -> std::io::Result<()> {
+    use std::fs::{create_dir_all, set_permissions, write, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let files = [
+        ("site/index.html", "<h1>hi</h1>"),
+        ("site/css/main.css", "h1 { color: red }"),
+        ("site/notes.txt", "not for publishing"),
+        ("tools/build.sh", "#!/bin/sh\necho built\n"),
+    ];
+    for (path, contents) in files {
+        let path = dir.join(path);
+        create_dir_all(path.parent().expect("files are nested"))?;
+        write(&path, contents)?;
+        set_permissions(&path, Permissions::from_mode(0o644))?;
+    }
+    set_permissions(dir.join("tools/build.sh"), Permissions::from_mode(0o755))?;
+    for sub in ["site", "site/css", "tools"] {
+        set_permissions(dir.join(sub), Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Run a pipeline over a fresh `golden_tree`, returning the final stack.
+///
+/// `{tree}` in any arg is replaced with the tree's location.
+#[cfg(test)]
+pub fn run_golden(args: &[&str]) -> std::io::Result<Vec<String>> {
+    let tree = tempfile::tempdir()?;
+    golden_tree(tree.path())?;
+    let tree_path = tree.path().to_str().expect("tempdir is unicode");
+
+    let db = arkive::DB::new_temp()?;
+    let mut log = crate::logger::Logger::new_vec();
+    let mut ctx = crate::context::Context::new(&db, &mut log);
+    ctx.parse_apply(
+        args.iter()
+            .map(|a| a.replace("{tree}", tree_path))
+            .collect(),
+    )?;
+    Ok(ctx.stack.iter().map(|d| d.to_hex()).collect())
+}
+
+/// A tiny xorshift generator, so randomized tests replay the same cases.
+#[cfg(test)]
+pub struct Rng(u32);
+
+#[cfg(test)]
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        assert_ne!(seed, 0, "xorshift never leaves zero");
+        Self(seed)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as usize % n
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// If one of these changes, some op now builds different archives than
+    /// it used to. That might be on purpose! But it breaks every cache and
+    /// every pinned digest out there, so update these deliberately.
+    #[test]
+    fn golden_pipelines() -> std::io::Result<()> {
+        let cases: &[(&[&str], &[&str])] = &[
+            (
+                &["--import", "{tree}", "site"],
+                &["ea26c6e3ec4cb641335620a48b8da3059f93bd1edf0178df420d1e61da779734"],
+            ),
+            (
+                &[
+                    "--import",
+                    "{tree}",
+                    "site",
+                    "tools",
+                    "--merge",
+                    "--filter",
+                    r"\.(html|css|sh)$",
+                    "--prefix",
+                    "www",
+                ],
+                &["977b2cc82ebb0b8ca78c632338294f3960660f14ecd3c1852e1aaea5f02becd2"],
+            ),
+            (
+                &[
+                    "--import",
+                    "{tree}",
+                    "site",
+                    "--flatten",
+                    "--rename",
+                    "^index",
+                    "home",
+                    "--file",
+                    "robots.txt",
+                    "User-agent: *",
+                ],
+                &["f0dd7fcc5787c5c3507a883ba7184f2500d586e920acf529e334c5dd934b9689"],
+            ),
+            (
+                &[
+                    "--empty", "--import", "{tree}", "tools", "--verify", "--merge",
+                ],
+                &["7cdd05a7721736e75cea4dc438626bed76574015e6f8dcda8ddc7765c3a08275"],
+            ),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(&run_golden(args)?, expected, "Pipeline {:?}", args);
+        }
+        Ok(())
+    }
+}
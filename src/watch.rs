@@ -0,0 +1,258 @@
+use crate::glob::is_pattern;
+use crate::op::Op;
+use crate::scan::{scan_with, ScanOptions};
+use arkive::Ark;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+/// How long the imports have to be quiet before `--watch` rebuilds.
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How often `--watch` scans the imports, when it can't get events for them.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Everything a pipeline imports from, which is what `--watch` keeps an eye on.
+pub fn sources(pipeline: &[Op]) -> Vec<PathBuf> {
+    pipeline
+        .iter()
+        .flat_map(|op| match op {
+            Op::Import { base, targets } => targets
+                .iter()
+                .map(|t| Path::new(base).join(fixed_part(t)))
+                .collect(),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// The part of a target before any glob, which is where matches can appear.
+fn fixed_part(target: &str) -> String {
+    target
+        .split('/')
+        .take_while(|part| !is_pattern(part))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Notices when files under some paths change.
+///
+/// Normally this listens for filesystem events, see `Watcher::events`. Where
+/// those aren't available, it falls back to scanning, see `Watcher::polling`.
+/// Either way, anything the pipeline writes back under a watched path counts
+/// as a change too.
+pub enum Watcher {
+    Events {
+        rx: Receiver<DebounceEventResult>,
+        // Stops watching when dropped.
+        _debouncer: Debouncer<RecommendedWatcher>,
+    },
+    Polling(Poller),
+}
+
+impl Watcher {
+    /// Watch with `notify`, getting each burst of events once it's settled.
+    ///
+    /// Fails if any source is missing, or the OS won't watch it, e.g. when
+    /// out of inotify watches.
+    pub fn events(sources: &[PathBuf]) -> Result<Self> {
+        let (tx, rx) = channel();
+        let mut debouncer = new_debouncer(DEBOUNCE, tx).map_err(Error::other)?;
+        for path in sources {
+            debouncer
+                .watcher()
+                .watch(path, RecursiveMode::Recursive)
+                .map_err(|e| Error::other(format!("{}: {}", path.display(), e)))?;
+        }
+        Ok(Self::Events {
+            rx,
+            _debouncer: debouncer,
+        })
+    }
+
+    /// Watch by scanning every source each `POLL_INTERVAL`.
+    pub fn polling(sources: Vec<PathBuf>, opts: &ScanOptions) -> Result<Self> {
+        Ok(Self::Polling(Poller::new(sources, opts)?))
+    }
+
+    /// Forget about any changes so far, like the ones a build just made.
+    pub fn forget(&mut self) -> Result<()> {
+        match self {
+            // Events can still be on their way, so wait for a quiet spell.
+            Self::Events { rx, .. } => while rx.recv_timeout(DEBOUNCE * 2).is_ok() {},
+            Self::Polling(poller) => {
+                poller.changed()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Block until something changes, and then things have settled.
+    pub fn wait(&mut self) -> Result<()> {
+        match self {
+            Self::Events { rx, .. } => loop {
+                match rx.recv() {
+                    Ok(Ok(events)) if !events.is_empty() => return Ok(()),
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(e)) => return Err(Error::other(e)),
+                    Err(_) => return Err(Error::other("File watcher stopped")),
+                }
+            },
+            Self::Polling(poller) => poller.wait(POLL_INTERVAL),
+        }
+    }
+}
+
+/// Notices when files under some paths change, by scanning them.
+///
+/// Each look is a full scan with mtimes, so this is for source trees, not
+/// for whole disks.
+pub struct Poller {
+    sources: Vec<PathBuf>,
+    opts: ScanOptions,
+    last: Vec<Option<Ark<PathBuf>>>,
+}
+
+impl Poller {
+    pub fn new(sources: Vec<PathBuf>, opts: &ScanOptions) -> Result<Self> {
+        let opts = ScanOptions {
+            full_metadata: true,
+            ..opts.clone()
+        };
+        let mut watcher = Self {
+            sources,
+            opts,
+            last: vec![],
+        };
+        watcher.last = watcher.look()?;
+        Ok(watcher)
+    }
+
+    /// Scan every source. A missing one is `None`, since it may come back.
+    fn look(&self) -> Result<Vec<Option<Ark<PathBuf>>>> {
+        self.sources
+            .iter()
+            .map(|path| match scan_with(path, &self.opts) {
+                Ok(ark) => Ok(Some(ark)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Has anything changed since the last call (or since `new`)?
+    pub fn changed(&mut self) -> Result<bool> {
+        let now = self.look()?;
+        if now == self.last {
+            return Ok(false);
+        }
+        self.last = now;
+        Ok(true)
+    }
+
+    /// Block until there's been a change, and then things have settled.
+    ///
+    /// Editors and `git checkout` touch files in bursts, so this waits for
+    /// one quiet interval before returning, rather than rebuilding per file.
+    pub fn wait(&mut self, interval: Duration) -> Result<()> {
+        while !self.changed()? {
+            std::thread::sleep(interval);
+        }
+        loop {
+            std::thread::sleep(interval);
+            if !self.changed()? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources() {
+        let pipeline = [
+            Op::Import {
+                base: "src".into(),
+                targets: vec!["a".into(), "b".into()],
+            },
+            Op::Empty,
+            Op::Import {
+                base: ".".into(),
+                targets: vec!["fixture".into(), "pkgs/*/src".into()],
+            },
+        ];
+        assert_eq!(
+            super::sources(&pipeline),
+            vec![
+                PathBuf::from("src/a"),
+                PathBuf::from("src/b"),
+                PathBuf::from("./fixture"),
+                PathBuf::from("./pkgs")
+            ]
+        );
+    }
+
+    #[test]
+    fn events() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src)?;
+
+        let mut watcher = Watcher::events(std::slice::from_ref(&src))?;
+        std::fs::write(src.join("a.txt"), "one")?;
+        let Watcher::Events { rx, .. } = &watcher else {
+            panic!("Should be using events");
+        };
+        let events = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Should see the write")
+            .map_err(Error::other)?;
+        assert!(events.iter().any(|e| e.path.ends_with("a.txt")));
+
+        std::fs::write(src.join("a.txt"), "two")?;
+        watcher.forget()?;
+        let Watcher::Events { rx, .. } = &watcher else {
+            panic!("Should be using events");
+        };
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn events_missing_source() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Watcher::events(&[dir.path().join("nope")]).is_err());
+    }
+
+    #[test]
+    fn changed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src)?;
+        std::fs::write(src.join("a.txt"), "one")?;
+
+        let mut watcher = Poller::new(vec![src.clone()], &ScanOptions::default())?;
+        assert!(!watcher.changed()?);
+
+        let later = filetime::FileTime::from_unix_time(2_000_000_000, 0);
+        std::fs::write(src.join("a.txt"), "two")?;
+        filetime::set_file_mtime(src.join("a.txt"), later)?;
+        assert!(watcher.changed()?);
+        assert!(!watcher.changed()?);
+
+        std::fs::remove_dir_all(&src)?;
+        assert!(watcher.changed()?);
+
+        // Already changed, and then quiet, so this returns straight away.
+        std::fs::create_dir(&src)?;
+        watcher.wait(Duration::ZERO)?;
+        assert!(!watcher.changed()?);
+        Ok(())
+    }
+}